@@ -0,0 +1,63 @@
+use crate::{*, utils::InternalError};
+
+#[near_bindgen]
+impl Contract {
+  // Registers a NEP-141 token the marketplace accepts payments in, along with its precision.
+  #[handle_result]
+  pub fn add_accepted_token(&mut self, token_id: AccountId, precision: u8) -> Result<(), InternalError> {
+    self.assert_owner()?;
+    self.accepted_tokens.insert(&token_id, &precision);
+    Ok(())
+  }
+
+  #[handle_result]
+  pub fn remove_accepted_token(&mut self, token_id: AccountId) -> Result<(), InternalError> {
+    self.assert_owner()?;
+    self.accepted_tokens.remove(&token_id).ok_or(InternalError::UnknownToken)?;
+    Ok(())
+  }
+
+  #[handle_result]
+  pub fn set_platform_fee(&mut self, platform_fee_bps: u16) -> Result<(), InternalError> {
+    self.assert_owner()?;
+    if platform_fee_bps > 10_000 {
+      return Err(InternalError::InvalidPayload("platform fee cannot exceed 10000 bps".to_string()))
+    }
+    self.config.platform_fee_bps = platform_fee_bps;
+    Ok(())
+  }
+
+  // Arbitrators settle disputed orders through `resolve_dispute`.
+  #[handle_result]
+  pub fn add_arbitrator(&mut self, account_id: AccountId) -> Result<(), InternalError> {
+    self.assert_owner()?;
+    if !self.arbitrators.insert(&account_id) {
+      return Err(InternalError::AlreadyExists)
+    }
+    Ok(())
+  }
+
+  #[handle_result]
+  pub fn remove_arbitrator(&mut self, account_id: AccountId) -> Result<(), InternalError> {
+    self.assert_owner()?;
+    if !self.arbitrators.remove(&account_id) {
+      return Err(InternalError::NotFound)
+    }
+    Ok(())
+  }
+
+  pub fn get_arbitrators(&self, page: u16, limit: u16) -> Vec<AccountId> {
+    self.arbitrators.iter().skip(page.into()).take(limit.into()).collect()
+  }
+
+  pub fn get_accepted_tokens(&self, page: u16, limit: u16) -> Vec<(AccountId, u8)> {
+    self.accepted_tokens.iter().skip(page.into()).take(limit.into()).collect()
+  }
+
+  pub(crate) fn assert_owner(&self) -> Result<(), InternalError> {
+    if env::predecessor_account_id() != self.owner_id {
+      return Err(InternalError::Unauthorized)
+    }
+    Ok(())
+  }
+}
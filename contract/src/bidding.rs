@@ -0,0 +1,195 @@
+use crate::{*, utils::{OpenOrder, OpenOrderView, OpenOrderBundle, Coordinate, InternalError, DTradeEvent, PlaceBidData, WithdrawBidData, cells_within, check_coordinate, distance_m, grid_cell}};
+
+const MAX_SEARCH_RADIUS_KM: u32 = 100;
+const MAX_SCANNED_ORDERS: u32 = 100;
+
+#[near_bindgen]
+impl Contract {
+  // Orders listed for bidding that have no courier yet.
+  pub fn get_open_orders(&self, page: u16, limit: u16) -> OpenOrderBundle {
+    let orders = self.open_orders.iter().skip(page.into()).take(limit.into()).filter_map(|(order_id, open_order)| {
+      self.open_order_view(order_id, open_order)
+    }).collect();
+    OpenOrderBundle {
+      next_page: page.saturating_add(limit),
+      orders
+    }
+  }
+
+  // Open orders picked up (at the seller's company) within `radius_km` of the courier and, when a
+  // vehicle is given, delivered within that vehicle's range. Only the grid cells around the courier
+  // are read, and at most `MAX_SCANNED_ORDERS` of their orders per call, so pages can be shorter
+  // than `limit`; `next_page` is where the next call resumes. Pages are offsets into the cells'
+  // sets, which reorder as orders leave the board, so between calls an order may be skipped or
+  // seen twice.
+  #[handle_result]
+  pub fn get_open_orders_near(&self, lat: i64, lon: i64, radius_km: u32, vehicle: Option<String>, page: u16, limit: u16) -> Result<OpenOrderBundle, InternalError> {
+    let courier_location = Coordinate { lat, lon };
+    check_coordinate(&courier_location)?;
+    let vehicle = vehicle.map(|vehicle| vehicle.parse::<Vehicle>()).transpose()?;
+    let radius_m = radius_km.min(MAX_SEARCH_RADIUS_KM) as u64 * 1_000;
+    let mut orders: Vec<OpenOrderView> = vec![];
+    let mut next_page = page;
+    let mut skip = page as u64;
+    let mut scanned = 0;
+
+    'cells: for cell in cells_within(&courier_location, radius_m) {
+      let cell_orders = match self.orders_by_cell.get(&cell) {
+        Some(cell_orders) => cell_orders,
+        None => continue
+      };
+      if skip >= cell_orders.len() {
+        skip -= cell_orders.len();
+        continue
+      }
+      for index in skip..cell_orders.len() {
+        if orders.len() >= limit.into() || scanned >= MAX_SCANNED_ORDERS {
+          break 'cells
+        }
+        scanned += 1;
+        next_page = next_page.saturating_add(1);
+        let order_id = cell_orders.as_vector().get(index).unwrap();
+        let view = match self.open_orders.get(&order_id).and_then(|open_order| self.open_order_view(order_id, open_order)) {
+          Some(view) => view,
+          None => continue
+        };
+        let pickup = match self.companies.get(&view.order.metadata.seller) {
+          Some(company) => company.location,
+          None => continue
+        };
+        if distance_m(&courier_location, &pickup) > radius_m {
+          continue
+        }
+        if let Some(vehicle) = vehicle.as_ref() {
+          if distance_m(&pickup, &view.order.metadata.location) > vehicle.range_m() {
+            continue
+          }
+        }
+        orders.push(view);
+      }
+      skip = 0;
+    }
+    Ok(OpenOrderBundle {
+      next_page,
+      orders
+    })
+  }
+
+  // Registered couriers bid on open orders until the bid deadline; bidding again updates the bid.
+  // The buyer picks a bid through `approve_proposal`.
+  #[handle_result]
+  pub fn place_bid(&mut self, order_id: String, fee: U128, eta_minutes: u32) -> Result<(), InternalError> {
+    let courier_id = env::predecessor_account_id();
+    let initial_storage = env::storage_usage();
+    if self.couriers.get(&courier_id).is_none() {
+      return Err(InternalError::Unauthorized)
+    }
+    let open_order = self.open_orders.get(&order_id).ok_or(InternalError::NotFound)?;
+    if env::block_timestamp_ms() > open_order.bid_deadline {
+      return Err(InternalError::Expired)
+    }
+
+    let mut courier_proposals = self.proposals.get(&courier_id).unwrap_or_else(|| {
+      let prefix: Vec<u8> = [
+          b"r".as_slice(),
+          &near_sdk::env::sha256_array(courier_id.as_bytes()),
+        ]
+        .concat();
+      UnorderedMap::new(prefix)
+    });
+    let is_new_bid = courier_proposals.get(&order_id).is_none();
+    let proposal = Proposal {
+      client: open_order.buyer_id.clone(),
+      courier_id: Some(courier_id.clone()),
+      amount: 0,
+      fee: fee.0,
+      status: ProposalStatus::PROPOSED,
+      eta_minutes: Some(eta_minutes)
+    };
+    courier_proposals.insert(&order_id, &proposal.into());
+    self.proposals.insert(&courier_id, &courier_proposals);
+    if is_new_bid {
+      let mut my_orders = self.orders.get(&open_order.buyer_id).ok_or(InternalError::NotFound)?;
+      let (order, items, mut couriers) = my_orders.get(&order_id).ok_or(InternalError::NotFound)?;
+      couriers.push(&courier_id);
+      my_orders.insert(&order_id, &(order, items, couriers));
+    }
+
+    DTradeEvent::PlaceBid(vec![PlaceBidData {
+      order_id: &order_id,
+      courier_id: &courier_id,
+      fee,
+      eta_minutes,
+    }]).emit();
+    self.charge_storage(&courier_id, initial_storage)
+  }
+
+  // A courier takes back any proposal on an order that has not been approved yet.
+  #[handle_result]
+  pub fn withdraw_bid(&mut self, order_id: String) -> Result<(), InternalError> {
+    let courier_id = env::predecessor_account_id();
+    let initial_storage = env::storage_usage();
+    let mut courier_proposals = self.proposals.get(&courier_id).ok_or(InternalError::NotFound)?;
+    let proposal = courier_proposals.get(&order_id).map(Proposal::from).ok_or(InternalError::NotFound)?;
+    if proposal.status == ProposalStatus::APPROVED {
+      return Err(InternalError::InvalidTransition)
+    }
+    courier_proposals.remove(&order_id);
+    self.proposals.insert(&courier_id, &courier_proposals);
+
+    if let Some(mut my_orders) = self.orders.get(&proposal.client) {
+      if let Some((order, items, mut couriers)) = my_orders.get(&order_id) {
+        if let Some(index) = couriers.iter().position(|id| id == courier_id) {
+          couriers.swap_remove(index as u64);
+          my_orders.insert(&order_id, &(order, items, couriers));
+        }
+      }
+    }
+
+    DTradeEvent::WithdrawBid(vec![WithdrawBidData {
+      order_id: &order_id,
+      courier_id: &courier_id,
+    }]).emit();
+    self.charge_storage(&courier_id, initial_storage)
+  }
+
+  // Puts an order on the bidding board and indexes it by the cell it is picked up from.
+  pub(crate) fn list_open_order(&mut self, order_id: &String, buyer_id: &AccountId, pickup: &Coordinate, bid_deadline: u64) {
+    let cell = grid_cell(pickup);
+    let open_order = OpenOrder {
+      buyer_id: buyer_id.clone(),
+      bid_deadline,
+      cell
+    };
+    self.open_orders.insert(order_id, &open_order);
+    let mut cell_orders = self.orders_by_cell.get(&cell).unwrap_or_else(|| {
+      let prefix: Vec<u8> = [
+          b"I".as_slice(),
+          &near_sdk::env::sha256_array(&[cell.0.to_le_bytes(), cell.1.to_le_bytes()].concat()),
+        ]
+        .concat();
+      UnorderedSet::new(prefix)
+    });
+    cell_orders.insert(order_id);
+    self.orders_by_cell.insert(&cell, &cell_orders);
+  }
+
+  pub(crate) fn delist_open_order(&mut self, order_id: &String) {
+    if let Some(open_order) = self.open_orders.remove(order_id) {
+      if let Some(mut cell_orders) = self.orders_by_cell.get(&open_order.cell) {
+        cell_orders.remove(order_id);
+        self.orders_by_cell.insert(&open_order.cell, &cell_orders);
+      }
+    }
+  }
+
+  pub(crate) fn open_order_view(&self, order_id: String, open_order: OpenOrder) -> Option<OpenOrderView> {
+    self.retrieve_order(open_order.buyer_id.clone(), order_id).map(|order| {
+      OpenOrderView {
+        buyer_id: open_order.buyer_id,
+        bid_deadline: open_order.bid_deadline,
+        order
+      }
+    })
+  }
+}
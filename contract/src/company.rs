@@ -0,0 +1,75 @@
+use crate::{*, utils::{CompanyDetails, CompanyView, CompanyBundle, StarRate, InternalError, check_coordinate}};
+
+#[near_bindgen]
+impl Contract {
+  // A seller registers a single company, keyed by the owner account that also keys its pending and staged orders.
+  #[handle_result]
+  pub fn register_company(&mut self, details: CompanyDetails) -> Result<AccountId, InternalError> {
+    let account_id = env::predecessor_account_id();
+    let id = account_id.clone();
+    let initial_storage = env::storage_usage();
+    check_coordinate(&details.location)?;
+    if self.companies.get(&id).is_some() {
+      return Err(InternalError::AlreadyExists)
+    }
+
+    let company = Company {
+      owner: account_id,
+      name: details.name,
+      wallet: details.wallet,
+      phone: details.phone,
+      email: details.email,
+      location: details.location,
+      sales: 0,
+      star_rate: StarRate {
+        voters: 0,
+        votes: 0
+      }
+    };
+    self.companies.insert(&id, &company);
+    self.charge_storage(&id, initial_storage)?;
+    Ok(id)
+  }
+
+  #[handle_result]
+  pub fn update_company(&mut self, details: CompanyDetails) -> Result<AccountId, InternalError> {
+    let account_id = env::predecessor_account_id();
+    let id = account_id.clone();
+    let initial_storage = env::storage_usage();
+    check_coordinate(&details.location)?;
+    let company = self.companies.get(&id).ok_or(InternalError::UnknownCompany)?;
+    if company.owner != account_id {
+      return Err(InternalError::Unauthorized)
+    }
+
+    let updated_company = Company {
+      name: details.name,
+      wallet: details.wallet,
+      phone: details.phone,
+      email: details.email,
+      location: details.location,
+      ..company
+    };
+    self.companies.insert(&id, &updated_company);
+    self.charge_storage(&id, initial_storage)?;
+    Ok(id)
+  }
+
+  pub fn get_company(&self, company_id: AccountId) -> Option<Company> {
+    self.companies.get(&company_id)
+  }
+
+  pub fn list_companies(&self, page: u16, limit: u16) -> CompanyBundle {
+    let companies = self.companies.iter().skip(page.into()).take(limit.into()).map(|(id, metadata)| {
+      CompanyView {
+        id,
+        metadata
+      }
+    }).collect::<Vec<CompanyView>>();
+    let next_page = page.saturating_add(limit);
+    CompanyBundle {
+      next_page,
+      companies
+    }
+  }
+}
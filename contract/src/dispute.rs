@@ -0,0 +1,113 @@
+use crate::{*, utils::{Dispute, DisputeStatus, InternalError, DTradeEvent, OpenDisputeData, ResolveDisputeData}};
+
+#[near_bindgen]
+impl Contract {
+  // Either party of an unsettled order may dispute it within the dispute window. Until an
+  // arbitrator resolves it the order cannot move, so its escrow can be neither released nor refunded.
+  #[handle_result]
+  pub fn open_dispute(&mut self, order_id: String, reason: String) -> Result<(), InternalError> {
+    let id = env::predecessor_account_id();
+    let initial_storage = env::storage_usage();
+    let buyer_id = self.order_buyer(&id, &order_id).ok_or(InternalError::NotFound)?;
+    let (order, _, _) = self.orders.get(&buyer_id).and_then(|my_orders| my_orders.get(&order_id)).ok_or(InternalError::NotFound)?;
+    let order = Order::from(order);
+    if !order.status.can_transition_to(&OrderStatus::RESOLVED) {
+      return Err(InternalError::InvalidTransition)
+    }
+    // Shipped orders stay disputable for as long as the buyer may still confirm them
+    let deadline = self.get_locked_balance(buyer_id.clone(), order_id.clone()).map(|locked_amount| locked_amount.deadline).unwrap_or(0);
+    let now = env::block_timestamp_ms();
+    if now > order.timestamp + self.config.dispute_window_ms && now > deadline {
+      return Err(InternalError::Expired)
+    }
+    if self.is_disputed(&order_id) {
+      return Err(InternalError::AlreadyExists)
+    }
+
+    let dispute = Dispute {
+      buyer_id,
+      seller_id: order.seller,
+      opened_by: id.clone(),
+      reason,
+      opened_at: env::block_timestamp_ms(),
+      status: DisputeStatus::OPEN,
+      buyer_share_bps: None,
+      resolved_by: None
+    };
+    self.disputes.insert(&order_id, &dispute);
+    DTradeEvent::OpenDispute(vec![OpenDisputeData {
+      order_id: &order_id,
+      buyer_id: &dispute.buyer_id,
+      seller_id: &dispute.seller_id,
+      opened_by: &id,
+      reason: &dispute.reason,
+    }]).emit();
+    self.charge_storage(&id, initial_storage)
+  }
+
+  // Splits the order's escrow: `buyer_share_bps` of it is refunded to the buyer and the rest
+  // is paid to the seller as if the order had been delivered.
+  #[handle_result]
+  pub fn resolve_dispute(&mut self, order_id: String, buyer_share_bps: u16) -> Result<(), InternalError> {
+    let arbitrator_id = env::predecessor_account_id();
+    if !self.arbitrators.contains(&arbitrator_id) {
+      return Err(InternalError::Unauthorized)
+    }
+    if buyer_share_bps > 10_000 {
+      return Err(InternalError::InvalidPayload("buyer share cannot exceed 10000 bps".to_string()))
+    }
+    let initial_storage = env::storage_usage();
+    let dispute = self.disputes.get(&order_id).ok_or(InternalError::NotFound)?;
+    if dispute.status != DisputeStatus::OPEN {
+      return Err(InternalError::InvalidTransition)
+    }
+    let buyer_id = dispute.buyer_id.clone();
+    self.settle_dispute(&order_id, dispute, buyer_share_bps, Some(arbitrator_id))?;
+    self.settle_storage(&buyer_id, initial_storage);
+    Ok(())
+  }
+
+  pub fn get_dispute(&self, order_id: String) -> Option<Dispute> {
+    self.disputes.get(&order_id)
+  }
+
+  // `resolved_by` is `None` when a timed out dispute is settled in the buyer's favour.
+  pub(crate) fn settle_dispute(&mut self, order_id: &String, mut dispute: Dispute, buyer_share_bps: u16, resolved_by: Option<AccountId>) -> Result<(), InternalError> {
+    let (order, _, _) = self.orders.get(&dispute.buyer_id).and_then(|my_orders| my_orders.get(order_id)).ok_or(InternalError::NotFound)?;
+    let order = Order::from(order);
+    // A courier who never picked the order up has not earned any of the fee
+    let courier = if matches!(order.status, OrderStatus::SHIPPING) { order.courier.clone() } else { None };
+    self.transition_order(&dispute.buyer_id, order_id, OrderStatus::RESOLVED)?;
+    if matches!(order.status, OrderStatus::PENDING | OrderStatus::STAGGED) {
+      self.refund_premium(&dispute.buyer_id, &order);
+    }
+    let (buyer_amount, seller_amount, courier_amount) = self.split(&dispute.buyer_id, order_id, buyer_share_bps, courier)?;
+    dispute.status = DisputeStatus::RESOLVED;
+    dispute.buyer_share_bps = Some(buyer_share_bps);
+    dispute.resolved_by = resolved_by;
+    self.disputes.insert(order_id, &dispute);
+    DTradeEvent::ResolveDispute(vec![ResolveDisputeData {
+      order_id,
+      arbitrator_id: dispute.resolved_by.as_ref(),
+      buyer_share_bps,
+      buyer_amount: U128(buyer_amount),
+      seller_amount: U128(seller_amount),
+      courier_amount: U128(courier_amount),
+    }]).emit();
+    Ok(())
+  }
+
+  pub(crate) fn is_disputed(&self, order_id: &String) -> bool {
+    matches!(self.disputes.get(order_id).map(|dispute| dispute.status), Some(DisputeStatus::OPEN))
+  }
+
+  // Finds the buyer of `order_id` when `account_id` is either its buyer or its seller.
+  fn order_buyer(&self, account_id: &AccountId, order_id: &String) -> Option<AccountId> {
+    if self.orders.get(account_id).and_then(|my_orders| my_orders.get(order_id)).is_some() {
+      return Some(account_id.clone())
+    }
+    [&self.orders_pending, &self.orders_staged, &self.orders_shipping].iter().find_map(|index| {
+      index.get(account_id).and_then(|seller_orders| seller_orders.get(order_id))
+    })
+  }
+}
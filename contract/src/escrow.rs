@@ -1,15 +1,25 @@
-use near_sdk::require;
+use near_sdk::{require, assert_one_yocto, ext_contract, Gas, Promise, PromiseResult};
 
-use crate::*;
+use crate::{*, utils::{InternalError, DisputeStatus, DTradeEvent, LockBalanceData, RefundData}};
+
+pub(crate) const GAS_FOR_FT_TRANSFER: Gas = Gas(10_000_000_000_000);
+pub(crate) const GAS_FOR_RESOLVE_WITHDRAW: Gas = Gas(10_000_000_000_000);
+
+#[allow(dead_code)]
+#[ext_contract(ext_ft)]
+pub(crate) trait FungibleToken {
+  fn ft_transfer(&mut self, receiver_id: AccountId, amount: U128, memo: Option<String>);
+}
 
 #[near_bindgen]
 impl Contract {
 
-  pub fn lock_balance(&mut self, account_id: &AccountId, escrow_id: &String, receiver_id: AccountId, lock_amount: u128) {
-   require!(lock_amount > 0, "Insufficient funds!");
-    let id: String = String::from_utf8(near_sdk::env::sha256(account_id.as_bytes()).to_vec()).unwrap();
-
-   let mut my_locked_balances = self.locked_balances.get(&id).unwrap_or_else(|| {
+  // Only reachable through `place_order`, which has just received the tokens being locked.
+  pub(crate) fn lock_balance(&mut self, account_id: &AccountId, escrow_id: &String, receiver_id: AccountId, token_id: AccountId, lock_amount: u128) -> Result<(), InternalError> {
+   if lock_amount == 0 {
+    return Err(InternalError::InsufficientFunds)
+   }
+   let mut my_locked_balances = self.locked_balances.get(account_id).unwrap_or_else(|| {
        let prefix: Vec<u8> = [
            b"a".as_slice(),
            &near_sdk::env::sha256_array(account_id.as_bytes()),
@@ -18,14 +28,19 @@ impl Contract {
        UnorderedMap::new(prefix)
    });
 
+   let deadline = env::block_timestamp_ms() + self.config.shipping_timeout_ms;
+   DTradeEvent::LockBalance(vec![LockBalanceData {
+    escrow_id, account_id, receiver_id: &receiver_id, token_id: &token_id, amount: U128(lock_amount), deadline
+   }]).emit();
    let locked_amount = LockedAmount {
-    receiver_id, amount: lock_amount
+    receiver_id, token_id: token_id.clone(), amount: lock_amount, deadline, courier_amount: 0
    };
 
-   my_locked_balances.insert(&escrow_id, &locked_amount);
+   my_locked_balances.insert(escrow_id, &locked_amount);
+   self.locked_balances.insert(account_id, &my_locked_balances);
   
-    let id: String = String::from_utf8(near_sdk::env::sha256(account_id.as_bytes()).to_vec()).unwrap();
-    let mut my_balance = self.balances.get(&id).unwrap_or_else(|| {
+    let key = (account_id.clone(), token_id);
+    let mut my_balance = self.balances.get(&key).unwrap_or_else(|| {
       Account {
         balance: 0,
         total_locked_balance: 0,
@@ -34,48 +49,237 @@ impl Contract {
     });
     
     my_balance.total_locked_balance += lock_amount;
-    self.balances.insert(&id, &my_balance);
+    self.balances.insert(&key, &my_balance);
+    Ok(())
   }
 
-  pub fn get_locked_balance(&self, account_id: AccountId, escrow_id: String,  receiver_id: AccountId) -> LockedAmount {
-    let id: String = String::from_utf8(near_sdk::env::sha256(account_id.as_bytes()).to_vec()).unwrap();
+  // The courier fee a buyer pays on approving a proposal is held with the order's escrow, so it
+  // reaches the courier on delivery and goes back to the buyer if the order is cancelled.
+  pub(crate) fn lock_courier_fee(&mut self, account_id: &AccountId, escrow_id: &String, amount: u128) -> Result<(), InternalError> {
+    let mut my_locked_balances = self.locked_balances.get(account_id).ok_or(InternalError::NotFound)?;
+    let locked_amount = my_locked_balances.get(escrow_id).ok_or(InternalError::NotFound)?;
+    let key = (account_id.clone(), locked_amount.token_id.clone());
+    let mut my_balance = self.balances.get(&key).ok_or(InternalError::NotFound)?;
+    my_balance.total_locked_balance += amount;
+    self.balances.insert(&key, &my_balance);
+    let courier_amount = locked_amount.courier_amount + amount;
+    my_locked_balances.insert(escrow_id, &LockedAmount { courier_amount, ..locked_amount });
+    Ok(())
+  }
 
-   if let Some(my_locked_balances) = self.locked_balances.get(&id) {
-    let locked_balance = my_locked_balances.get(&escrow_id).unwrap_or(LockedAmount { receiver_id, amount: 0 });
-    locked_balance
-   } else {
-    LockedAmount {
-     receiver_id, amount: 0
+  pub(crate) fn extend_lock(&mut self, account_id: &AccountId, escrow_id: &String, deadline: u64) -> Result<(), InternalError> {
+    let mut my_locked_balances = self.locked_balances.get(account_id).ok_or(InternalError::NotFound)?;
+    let locked_amount = my_locked_balances.get(escrow_id).ok_or(InternalError::NotFound)?;
+    my_locked_balances.insert(escrow_id, &LockedAmount { deadline, ..locked_amount });
+    Ok(())
+  }
+
+  // Settles an escrow nobody acted on in time: an order that was never shipped is refunded to
+  // the buyer, a shipped one the buyer neither confirmed nor disputed is paid out to the seller.
+  #[handle_result]
+  pub fn claim_expired(&mut self, order_id: String) -> Result<OrderStatus, InternalError> {
+    let initial_storage = env::storage_usage();
+    let buyer_id = self.order_buyers.get(&order_id).ok_or(InternalError::NotFound)?;
+    let locked_amount = self.get_locked_balance(buyer_id.clone(), order_id.clone()).ok_or(InternalError::NotFound)?;
+    if env::block_timestamp_ms() <= locked_amount.deadline {
+      return Err(InternalError::TooEarly)
     }
+    let (order, _, _) = self.orders.get(&buyer_id).and_then(|my_orders| my_orders.get(&order_id)).ok_or(InternalError::NotFound)?;
+    let status = match Order::from(order).status {
+      OrderStatus::PENDING | OrderStatus::STAGGED => {
+        self.cancel_and_refund(&buyer_id, order_id)?;
+        OrderStatus::CANCELLED
+      },
+      OrderStatus::SHIPPING => {
+        let order = self.transition_order(&buyer_id, &order_id, OrderStatus::DELIVERED)?;
+        self.release(&buyer_id, &order_id, order.courier)?;
+        OrderStatus::DELIVERED
+      },
+      _ => return Err(InternalError::InvalidTransition)
+    };
+    self.settle_storage(&buyer_id, initial_storage);
+    Ok(status)
+  }
+
+  pub fn get_locked_balance(&self, account_id: AccountId, escrow_id: String) -> Option<LockedAmount> {
+   if let Some(my_locked_balances) = self.locked_balances.get(&account_id) {
+    my_locked_balances.get(&escrow_id)
+   } else {
+    None
    }
   }
 
-  pub fn refund(&mut self, account_id: AccountId, escrow_id: String) -> AccountId {
-    let id: String = String::from_utf8(near_sdk::env::sha256(account_id.as_bytes()).to_vec()).unwrap();
+  // Cancels an order and returns its escrow to the buyer. Only the seller or an arbitrator may
+  // do so, or anyone once a dispute on the order has outlived the arbitration timeout.
+  #[handle_result]
+  pub fn refund(&mut self, account_id: AccountId, escrow_id: String) -> Result<AccountId, InternalError> {
+    let caller_id = env::predecessor_account_id();
+    let initial_storage = env::storage_usage();
+    let (order, _, _) = self.orders.get(&account_id).and_then(|my_orders| my_orders.get(&escrow_id)).ok_or(InternalError::NotFound)?;
+    let order = Order::from(order);
+    let is_arbitrator = self.arbitrators.contains(&caller_id);
 
-    let mut my_locked_balances = self.locked_balances.get(&id).unwrap_or_else(|| {
-      env::panic_str("No records found!");
-    });
+    if let Some(dispute) = self.disputes.get(&escrow_id).filter(|dispute| dispute.status == DisputeStatus::OPEN) {
+      let timed_out = env::block_timestamp_ms() > dispute.opened_at + self.config.arbitration_timeout_ms;
+      if !is_arbitrator && !timed_out {
+        return Err(InternalError::Unauthorized)
+      }
+      let resolved_by = if is_arbitrator { Some(caller_id) } else { None };
+      self.settle_dispute(&escrow_id, dispute, 10_000, resolved_by)?;
+      self.settle_storage(&account_id, initial_storage);
+      return Ok(order.seller)
+    }
+
+    if caller_id != order.seller && !is_arbitrator {
+      return Err(InternalError::Unauthorized)
+    }
+    let seller_id = self.cancel_and_refund(&account_id, escrow_id)?;
+    self.settle_storage(&account_id, initial_storage);
+    Ok(seller_id)
+  }
+
+  pub(crate) fn refund_escrow(&mut self, account_id: AccountId, escrow_id: String) -> Result<AccountId, InternalError> {
+    let mut my_locked_balances = self.locked_balances.get(&account_id).ok_or(InternalError::NotFound)?;
     
-    let locked_balance = my_locked_balances.get(&escrow_id).unwrap_or_else(|| {
-      env::panic_str("Invalid Order ID!");
-    });
-    let refund_amount = locked_balance.amount;
+    let locked_balance = my_locked_balances.get(&escrow_id).ok_or(InternalError::NotFound)?;
+    let refund_amount = locked_balance.amount + locked_balance.courier_amount;
     let receiver_id = locked_balance.receiver_id;
 
-    let mut my_balance = self.balances.get(&id).unwrap_or_else(|| {
-      Account {
-        balance: 0,
-        total_locked_balance: 0,
-        account_id
-      }
+    DTradeEvent::Refund(vec![RefundData {
+      escrow_id: &escrow_id,
+      account_id: &account_id,
+      token_id: &locked_balance.token_id,
+      amount: U128(refund_amount),
+    }]).emit();
+
+    let key = (account_id.clone(), locked_balance.token_id);
+    let mut my_balance = self.balances.get(&key).unwrap_or(Account {
+      balance: 0,
+      total_locked_balance: 0,
+      account_id: account_id.clone()
     });
 
     my_balance.balance += refund_amount;
     my_balance.total_locked_balance -= refund_amount;
-    self.balances.insert(&id, &my_balance);
+    self.balances.insert(&key, &my_balance);
     my_locked_balances.remove(&escrow_id);
-    receiver_id
+    self.locked_balances.insert(&account_id, &my_locked_balances);
+    Ok(receiver_id)
+  }
+
+  // Settles an escrow once delivery is confirmed: the locked order total goes to
+  // the seller's wallet and the locked courier fee goes to the courier, both less
+  // the platform fee.
+  pub(crate) fn release(&mut self, account_id: &AccountId, escrow_id: &String, courier: Option<AccountId>) -> Result<AccountId, InternalError> {
+    let mut my_locked_balances = self.locked_balances.get(account_id).ok_or(InternalError::NotFound)?;
+
+    let locked_balance = my_locked_balances.remove(escrow_id).ok_or(InternalError::NotFound)?;
+    self.locked_balances.insert(account_id, &my_locked_balances);
+
+    let key = (account_id.clone(), locked_balance.token_id.clone());
+    let mut my_balance = self.balances.get(&key).ok_or(InternalError::NotFound)?;
+    my_balance.total_locked_balance -= locked_balance.amount + locked_balance.courier_amount;
+    self.balances.insert(&key, &my_balance);
+
+    let seller_amount = self.collect_fee(&locked_balance.token_id, locked_balance.amount);
+    self.credit_balance(&locked_balance.receiver_id, &locked_balance.token_id, seller_amount);
+
+    if locked_balance.courier_amount > 0 {
+      match courier {
+        Some(courier_id) => {
+          let courier_amount = self.collect_fee(&locked_balance.token_id, locked_balance.courier_amount);
+          self.credit_balance(&courier_id, &locked_balance.token_id, courier_amount);
+        },
+        None => self.credit_balance(account_id, &locked_balance.token_id, locked_balance.courier_amount)
+      }
+    }
+    Ok(locked_balance.receiver_id)
+  }
+
+  // Settles a disputed escrow: `buyer_share_bps` of the locked amount goes back to the buyer
+  // and the rest to the seller's wallet, less the platform fee. The courier fee is split the
+  // same way with `courier`, who only has a claim to it once the order was shipped; without
+  // one it is refunded in full. Returns the buyer, seller and courier amounts.
+  pub(crate) fn split(&mut self, account_id: &AccountId, escrow_id: &String, buyer_share_bps: u16, courier: Option<AccountId>) -> Result<(u128, u128, u128), InternalError> {
+    let mut my_locked_balances = self.locked_balances.get(account_id).ok_or(InternalError::NotFound)?;
+
+    let locked_balance = my_locked_balances.remove(escrow_id).ok_or(InternalError::NotFound)?;
+    self.locked_balances.insert(account_id, &my_locked_balances);
+
+    let key = (account_id.clone(), locked_balance.token_id.clone());
+    let mut my_balance = self.balances.get(&key).ok_or(InternalError::NotFound)?;
+    my_balance.total_locked_balance -= locked_balance.amount + locked_balance.courier_amount;
+    self.balances.insert(&key, &my_balance);
+
+    let buyer_amount = treasury::bps_share(locked_balance.amount, buyer_share_bps);
+    let seller_amount = locked_balance.amount - buyer_amount;
+    let courier_amount = match courier.as_ref() {
+      Some(_) => locked_balance.courier_amount - treasury::bps_share(locked_balance.courier_amount, buyer_share_bps),
+      None => 0
+    };
+    let buyer_amount = buyer_amount + locked_balance.courier_amount - courier_amount;
+    self.credit_balance(account_id, &locked_balance.token_id, buyer_amount);
+    let seller_net = self.collect_fee(&locked_balance.token_id, seller_amount);
+    self.credit_balance(&locked_balance.receiver_id, &locked_balance.token_id, seller_net);
+    if let Some(courier_id) = courier.filter(|_| courier_amount > 0) {
+      let courier_net = self.collect_fee(&locked_balance.token_id, courier_amount);
+      self.credit_balance(&courier_id, &locked_balance.token_id, courier_net);
+    }
+    Ok((buyer_amount, seller_amount, courier_amount))
+  }
+
+  pub fn get_balance(&self, account_id: AccountId, token_id: AccountId) -> U128 {
+    U128(self.balances.get(&(account_id, token_id)).map(|account| account.balance).unwrap_or(0))
+  }
+
+  // Sends credited funds (refunds, sales, delivery fees) back to the caller's wallet.
+  // The balance is debited up front and restored in `resolve_withdraw` if the transfer fails.
+  #[payable]
+  #[handle_result]
+  pub fn withdraw(&mut self, token_id: AccountId, amount: U128) -> Result<Promise, InternalError> {
+    assert_one_yocto();
+    let account_id = env::predecessor_account_id();
+    let key = (account_id.clone(), token_id.clone());
+
+    let mut my_balance = self.balances.get(&key).ok_or(InternalError::NotFound)?;
+    if amount.0 == 0 || my_balance.balance < amount.0 {
+      return Err(InternalError::InsufficientFunds)
+    }
+    my_balance.balance -= amount.0;
+    self.balances.insert(&key, &my_balance);
+
+    Ok(ext_ft::ext(token_id.clone())
+      .with_attached_deposit(1)
+      .with_static_gas(GAS_FOR_FT_TRANSFER)
+      .ft_transfer(account_id.clone(), amount, Some("D-Trade withdrawal".to_string()))
+      .then(
+        Self::ext(env::current_account_id())
+          .with_static_gas(GAS_FOR_RESOLVE_WITHDRAW)
+          .resolve_withdraw(account_id, token_id, amount)
+      ))
+  }
+
+  #[private]
+  pub fn resolve_withdraw(&mut self, account_id: AccountId, token_id: AccountId, amount: U128) -> U128 {
+    require!(env::promise_results_count() == 1, "Unexpected promise results!");
+    match env::promise_result(0) {
+      PromiseResult::Successful(_) => amount,
+      _ => {
+        self.credit_balance(&account_id, &token_id, amount.0);
+        U128(0)
+      }
+    }
+  }
+
+  pub(crate) fn credit_balance(&mut self, account_id: &AccountId, token_id: &AccountId, amount: u128) {
+    let key = (account_id.clone(), token_id.clone());
+    let mut account = self.balances.get(&key).unwrap_or(Account {
+      balance: 0,
+      total_locked_balance: 0,
+      account_id: account_id.clone()
+    });
+    account.balance += amount;
+    self.balances.insert(&key, &account);
   }
 
 }
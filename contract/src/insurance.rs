@@ -0,0 +1,101 @@
+use crate::{*, utils::{InsuranceClaim, ClaimReason, ClaimStatus, InternalError}};
+
+#[near_bindgen]
+impl Contract {
+  // A buyer reports an insured order as lost or damaged while it is out for delivery. Until the
+  // owner decides on the claim the order cannot be settled as delivered.
+  #[handle_result]
+  pub fn file_claim(&mut self, order_id: String, reason: ClaimReason) -> Result<(), InternalError> {
+    let buyer_id = env::predecessor_account_id();
+    let initial_storage = env::storage_usage();
+    let (order, _, _) = self.orders.get(&buyer_id).and_then(|my_orders| my_orders.get(&order_id)).ok_or(InternalError::NotFound)?;
+    let order = Order::from(order);
+    if order.insurance == 0 {
+      return Err(InternalError::InvalidPayload("order is not insured".to_string()))
+    }
+    if !matches!(order.status, OrderStatus::SHIPPING) {
+      return Err(InternalError::InvalidTransition)
+    }
+    // A rejected claim may be filed again, a pending one may not
+    if let Some(ClaimStatus::PENDING) = self.insurance_claims.get(&order_id).map(|claim| claim.status) {
+      return Err(InternalError::AlreadyExists)
+    }
+
+    let claim = InsuranceClaim {
+      buyer_id: buyer_id.clone(),
+      reason,
+      status: ClaimStatus::PENDING,
+      filed_at: env::block_timestamp_ms(),
+      payout: 0
+    };
+    self.insurance_claims.insert(&order_id, &claim);
+    self.charge_storage(&buyer_id, initial_storage)
+  }
+
+  // The seller handed the goods over and the courier carried them, so the escrow is still
+  // settled; the buyer is then compensated for the order total out of the pool, up to what
+  // the pool holds.
+  #[handle_result]
+  pub fn approve_claim(&mut self, order_id: String) -> Result<U128, InternalError> {
+    self.assert_owner()?;
+    let initial_storage = env::storage_usage();
+    let mut claim = self.insurance_claims.get(&order_id).ok_or(InternalError::NotFound)?;
+    if claim.status != ClaimStatus::PENDING {
+      return Err(InternalError::InvalidTransition)
+    }
+    let order = self.transition_order(&claim.buyer_id, &order_id, OrderStatus::CLAIMED)?;
+    self.release(&claim.buyer_id, &order_id, order.courier.clone())?;
+
+    let pooled = self.insurance_pool.get(&order.token_id).unwrap_or(0);
+    let payout = order.amount.min(pooled);
+    self.insurance_pool.insert(&order.token_id, &(pooled - payout));
+    self.credit_balance(&claim.buyer_id, &order.token_id, payout);
+
+    claim.status = ClaimStatus::APPROVED;
+    claim.payout = payout;
+    self.insurance_claims.insert(&order_id, &claim);
+    self.settle_storage(&claim.buyer_id, initial_storage);
+    Ok(U128(payout))
+  }
+
+  #[handle_result]
+  pub fn reject_claim(&mut self, order_id: String) -> Result<(), InternalError> {
+    self.assert_owner()?;
+    let mut claim = self.insurance_claims.get(&order_id).ok_or(InternalError::NotFound)?;
+    if claim.status != ClaimStatus::PENDING {
+      return Err(InternalError::InvalidTransition)
+    }
+    claim.status = ClaimStatus::REJECTED;
+    self.insurance_claims.insert(&order_id, &claim);
+    Ok(())
+  }
+
+  pub fn get_claim(&self, order_id: String) -> Option<InsuranceClaim> {
+    self.insurance_claims.get(&order_id)
+  }
+
+  pub fn get_insurance_pool(&self) -> Vec<(AccountId, U128)> {
+    self.insurance_pool.iter().map(|(token_id, amount)| (token_id, U128(amount))).collect()
+  }
+
+  pub(crate) fn is_claim_pending(&self, order_id: &String) -> bool {
+    matches!(self.insurance_claims.get(order_id).map(|claim| claim.status), Some(ClaimStatus::PENDING))
+  }
+
+  pub(crate) fn pool_premium(&mut self, token_id: &AccountId, premium: u128) {
+    if premium > 0 {
+      let pooled = self.insurance_pool.get(token_id).unwrap_or(0);
+      self.insurance_pool.insert(token_id, &(pooled + premium));
+    }
+  }
+
+  // Orders that never shipped were never at risk, so their premium goes back to the buyer.
+  pub(crate) fn refund_premium(&mut self, buyer_id: &AccountId, order: &Order) {
+    let pooled = self.insurance_pool.get(&order.token_id).unwrap_or(0);
+    let premium = treasury::bps_share(order.amount, order.insurance as u16 * 100).min(pooled);
+    if premium > 0 {
+      self.insurance_pool.insert(&order.token_id, &(pooled - premium));
+      self.credit_balance(buyer_id, &order.token_id, premium);
+    }
+  }
+}
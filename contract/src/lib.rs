@@ -5,88 +5,156 @@
  * https://near-docs.io/develop/Contract
  *
  */
+#![allow(clippy::needless_return, clippy::upper_case_acronyms)]
 
+mod admin;
+mod bidding;
+mod company;
+mod dispute;
 mod escrow;
+mod insurance;
+mod migration;
+mod storage;
+mod tracking;
 mod transaction;
+mod treasury;
+mod upgrade;
 mod utils;
 
 use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
-use near_sdk::collections::{LookupMap, UnorderedMap, Vector};
-use near_sdk::{env, near_bindgen, AccountId};
+use near_sdk::collections::{LookupMap, UnorderedMap, UnorderedSet, Vector};
+use near_sdk::{env, near_bindgen, require, AccountId, FunctionError, PanicOnDefault};
 use near_sdk::json_types::U128;
-use utils::{Account, LockedAmount, OrderItem, Order, UserOrder, OrderBundle, OrderStatus, User, CourierUser, Vehicle, Courier, Company, CourierProfile, Proposal, ProposalStatus, CourierClientView};
+use utils::{Account, StorageAccount, Config, InsuranceClaim, Dispute, OpenOrder, GridCell, Checkpoint, LockedAmount, OrderItem, Order, UserOrder, OrderBundle, OrderStatus, User, CourierUser, Vehicle, Courier, Company, CourierProfile, Proposal, ProposalStatus, CourierClientView, InternalError, VersionedOrder, VersionedProposal, VersionedUser, DTradeEvent, StageOrderData, RegisterCourierData, SaveCompanyData};
 
 
+const MIN_DELIVERY_CODE_BYTES: usize = 16;
+
+// An order with its items and the couriers it was offered to
+type OrderRecord = (VersionedOrder, Vector<OrderItem>, Vector<AccountId>);
+// seller -> order_id -> buyer, one for each order status sellers act on
+type SellerOrders = LookupMap<AccountId, UnorderedMap<String, AccountId>>;
+
 // Define the contract structure
 #[near_bindgen]
-#[derive(BorshDeserialize, BorshSerialize)]
+#[derive(BorshDeserialize, BorshSerialize, PanicOnDefault)]
 pub struct Contract {
-    token_precision: u8,
-    balances: LookupMap<String, Account>,
-    locked_balances: LookupMap<String, UnorderedMap<String, LockedAmount>>,
-    orders: LookupMap<String, UnorderedMap<String, (Order, Vector<OrderItem>, Vector<String>)>>,
-    orders_pending: LookupMap<String, UnorderedMap<String, String>>,
-    orders_staged: LookupMap<String, UnorderedMap<String, String>>,
-    orders_shipping: LookupMap<String, UnorderedMap<String, String>>,
-    couriers: LookupMap<String, User>,
-    couriers_by_company: LookupMap<String, UnorderedMap<String, u64>>,
-    courier_companies: LookupMap<String, Vector<String>>,
-    companies: LookupMap<String, Company>,
-    proposals: LookupMap<String, UnorderedMap<String, Proposal>> // courier -> order_id -> proposal
+    state_version: u16, // must stay the first field, see `migrate`
+    owner_id: AccountId,
+    config: Config,
+    order_nonce: u64,
+    accepted_tokens: UnorderedMap<AccountId, u8>, // token -> precision
+    balances: LookupMap<(AccountId, AccountId), Account>, // (account, token) -> account
+    locked_balances: LookupMap<AccountId, UnorderedMap<String, LockedAmount>>,
+    orders: LookupMap<AccountId, UnorderedMap<String, OrderRecord>>,
+    orders_pending: SellerOrders,
+    orders_staged: SellerOrders,
+    orders_shipping: SellerOrders,
+    couriers: LookupMap<AccountId, VersionedUser>,
+    couriers_by_company: LookupMap<AccountId, UnorderedMap<AccountId, u64>>,
+    courier_companies: LookupMap<AccountId, Vector<AccountId>>,
+    companies: UnorderedMap<AccountId, Company>,
+    proposals: LookupMap<AccountId, UnorderedMap<String, VersionedProposal>>, // courier -> order_id -> proposal
+    storage_accounts: LookupMap<AccountId, StorageAccount>,
+    treasury: UnorderedMap<AccountId, u128>, // token -> collected platform fees
+    insurance_pool: UnorderedMap<AccountId, u128>, // token -> pooled premiums
+    insurance_claims: LookupMap<String, InsuranceClaim>, // order_id -> claim
+    arbitrators: UnorderedSet<AccountId>,
+    disputes: LookupMap<String, Dispute>, // order_id -> dispute
+    order_buyers: LookupMap<String, AccountId>, // order_id -> buyer
+    open_orders: UnorderedMap<String, OpenOrder>, // order_id -> orders couriers can bid on
+    orders_by_cell: LookupMap<GridCell, UnorderedSet<String>>, // pickup cell -> open order ids
+    tracking: LookupMap<String, Vector<Checkpoint>>, // order_id -> checkpoints posted by its courier
+    delivery_codes: LookupMap<String, Vec<u8>>, // order_id -> sha256 of the buyer's delivery code
+    active_shipments: LookupMap<AccountId, u32> // courier -> orders being shipped
 }
 
-// Define the default, which automatically initializes the contract
-impl Default for Contract{
-    fn default() -> Self{
+// Implement the contract structure
+#[near_bindgen]
+impl Contract {
+    #[init]
+    pub fn new(owner_id: AccountId, config: Config) -> Self {
+        require!(!env::state_exists(), "Already initialized");
+        Self::init(owner_id, config)
+    }
+
+    // Fresh state; `migrate` also starts from it when converting the first release.
+    fn init(owner_id: AccountId, config: Config) -> Self {
+        require!(config.platform_fee_bps <= 10_000, "Platform fee cannot exceed 10000 bps");
+        let mut accepted_tokens = UnorderedMap::new(b"v");
+        for (token_id, precision) in config.accepted_tokens.iter() {
+            accepted_tokens.insert(token_id, precision);
+        }
         Self{
-            token_precision: 18,
+            state_version: migration::STATE_VERSION,
+            owner_id,
+            config: Config {
+                accepted_tokens: vec![],
+                ..config
+            },
+            order_nonce: 0,
+            accepted_tokens, // v
             balances: LookupMap::new(b"b"), // b
             locked_balances: LookupMap::new(b"l"), // l, a
             orders: LookupMap::new(b"o"), // o, n, p, s
             orders_pending: LookupMap::new(b"c"), // c, f
-            orders_staged: LookupMap::new(b"d"), // d
-            orders_shipping: LookupMap::new(b"e"), // e
+            orders_staged: LookupMap::new(b"d"), // d, t
+            orders_shipping: LookupMap::new(b"e"), // e, u
             couriers: LookupMap::new(b"g"), // g
             couriers_by_company: LookupMap::new(b"h"), // h
-            companies: LookupMap::new(b"i"), // i, j
+            companies: UnorderedMap::new(b"i"), // i, j
             courier_companies: LookupMap::new(b"k"), // k, m
-            proposals: LookupMap::new(b"q") // q, r
+            proposals: LookupMap::new(b"q"), // q, r
+            storage_accounts: LookupMap::new(b"w"), // w
+            treasury: UnorderedMap::new(b"z"), // z
+            insurance_pool: UnorderedMap::new(b"A"), // A
+            insurance_claims: LookupMap::new(b"B"), // B
+            arbitrators: UnorderedSet::new(b"C"), // C
+            disputes: LookupMap::new(b"D"), // D
+            order_buyers: LookupMap::new(b"E"), // E
+            open_orders: UnorderedMap::new(b"G"), // G
+            orders_by_cell: LookupMap::new(b"H"), // H, I
+            tracking: LookupMap::new(b"J"), // J, K
+            delivery_codes: LookupMap::new(b"L"), // L
+            active_shipments: LookupMap::new(b"M") // M
+        }
+    }
+
+    pub fn get_config(&self) -> Config {
+        Config {
+            accepted_tokens: self.accepted_tokens.to_vec(),
+            ..self.config
         }
     }
-}
 
-// Implement the contract structure
-#[near_bindgen]
-impl Contract {
     pub fn ft_on_transfer(&mut self, sender_id: AccountId, amount: U128, msg: String) -> String {
-        let raw_msg = msg.split("|").collect::<Vec<&str>>();
-        let txn_type = raw_msg[0];
-        let txn = raw_msg[1];
-        match txn_type {
-            "place_order" => {
-                if let Some(balance) = self.place_order(sender_id, amount.0, txn.to_string(), self.token_precision) {
-                    return balance.to_string()
-                } else {
-                    return amount.0.to_string()
-                }
-            },
-            "approve_proposal" => {
-                if let Some(balance) = self.approve_proposal(sender_id, amount.0, txn.to_string()) {
-                    return balance.to_string()
-                } else {
-                    return amount.0.to_string()
-                }
+        let token_id = env::predecessor_account_id();
+        if self.accepted_tokens.get(&token_id).is_none() {
+            InternalError::UnknownToken.panic();
+        }
+        let initial_storage = env::storage_usage();
+        let result = match msg.split_once('|') {
+            Some(("place_order", txn)) => self.place_order(sender_id.clone(), token_id, amount.0, txn.to_string()),
+            Some(("approve_proposal", txn)) => self.approve_proposal(sender_id.clone(), token_id, amount.0, txn.to_string()),
+            // Some(("ship", txn)) => {},
+            _ => Err(InternalError::InvalidPayload(format!("unknown transaction: {}", msg)))
+        };
+        match result {
+            Ok(balance) => {
+                // Panicking hands the whole amount back through the token's resolve callback
+                self.charge_storage(&sender_id, initial_storage).unwrap_or_else(|error| error.panic());
+                balance.to_string()
             },
-            // "ship" => {},
-            _ => {
-                return amount.0.to_string()
+            Err(error) => {
+                // Nothing was stored, so the full amount goes back to the sender
+                env::log_str(&format!("Refunding {}: {}", amount.0, error));
+                amount.0.to_string()
             }
         }
     }
 
     pub fn get_buyer_orders(&self, page: u16, limit: u16) -> Option<OrderBundle> {
-        let account_id = env::predecessor_account_id();
-        let id: String = String::from_utf8(near_sdk::env::sha256(account_id.as_bytes()).to_vec()).unwrap();
+        let id = env::predecessor_account_id();
         if let Some(my_orders) = self.orders.get(&id) {
             let mut orders: Vec<UserOrder> = vec![];
             my_orders.keys().skip(page.into()).take(limit.into()).for_each(|key| {
@@ -94,12 +162,12 @@ impl Contract {
                 let _products = persisted_order.1.iter().collect::<Vec<OrderItem>>();
                 let user_order = UserOrder {
                     id: key,
-                    metadata: persisted_order.0,
+                    metadata: persisted_order.0.into(),
                     products: _products,
                 };
                 orders.push(user_order);
             });
-            let next_page = page + limit;
+            let next_page = page.saturating_add(limit);
             let bundled_order = OrderBundle {
                 next_page,
                 orders
@@ -109,12 +177,14 @@ impl Contract {
         None
     }
 
-    pub fn get_shipping_suggestions(&self, buyer_id: String, order_id: String, page: u16, limit: u16) -> Vec<CourierClientView> {
+    #[handle_result]
+    pub fn get_shipping_suggestions(&self, buyer_id: AccountId, order_id: String, page: u16, limit: u16) -> Result<Vec<CourierClientView>, InternalError> {
         let mut suggestions: Vec<CourierClientView> = vec![];
-        if let Some(order) = self.orders.get(&buyer_id).unwrap().get(&order_id) {
+        if let Some(order) = self.orders.get(&buyer_id).ok_or(InternalError::NotFound)?.get(&order_id) {
             order.2.iter().skip(page.into()).take(limit.into()).for_each(|courier_id| {
-                if let Some(proposal) = self.proposals.get(&courier_id).unwrap().get(&order_id) {
-                    let courier = self.couriers.get(&courier_id).unwrap();
+                let proposal = self.proposals.get(&courier_id).and_then(|proposals| proposals.get(&order_id)).map(Proposal::from);
+                let courier = self.couriers.get(&courier_id).map(User::from);
+                if let (Some(proposal), Some(courier)) = (proposal, courier) {
                     let profile = courier.courier_profile.unwrap();
                     let courier_client_view = CourierClientView {
                         id: courier_id,
@@ -124,53 +194,66 @@ impl Contract {
                         on_transit: profile.on_transit,
                         feedback: profile.feedback,
                         proposed_fee: proposal.fee,
+                        eta_minutes: proposal.eta_minutes,
                     };
                     suggestions.push(courier_client_view)
                 }
             });
         }
-        return suggestions
+        return Ok(suggestions)
     }
 
-    pub fn clear_order_couriers(&self, order_id: String, limit: u16) -> Vec<String> {
-        let account_id = env::predecessor_account_id();
-        let id = self.get_hash(account_id);
-        let mut order = self.orders.get(&id).unwrap().get(&order_id).unwrap();
+    #[handle_result]
+    pub fn clear_order_couriers(&mut self, order_id: String, limit: u16) -> Result<Vec<AccountId>, InternalError> {
+        let id = env::predecessor_account_id();
+        let initial_storage = env::storage_usage();
+        let mut my_orders = self.orders.get(&id).ok_or(InternalError::NotFound)?;
+        let (order, items, mut couriers) = my_orders.get(&order_id).ok_or(InternalError::NotFound)?;
+        let order = Order::from(order);
         
-        let approved_courier = order.0.courier.unwrap();
-        let mut page = order.2.len().min(limit as u64);
-        let mut courier_ids: Vec<String> = vec![];
+        // Bids were paid for by their couriers and proposals by the buyer, so each removed
+        // proposal is credited to whoever stored it; the rest of the freed bytes go to the buyer.
+        let mut proposal_bytes_freed = 0;
+        let approved_courier = order.courier.clone().ok_or(InternalError::CourierNotAssigned)?;
+        let mut page = couriers.len().min(limit as u64);
+        let mut courier_ids: Vec<AccountId> = vec![];
         
         while page > 0 {
-            let len = order.2.len();
-            let courier_id = order.2.get(len - 1).unwrap();
+            let len = couriers.len();
+            let courier_id = couriers.get(len - 1).unwrap();
             if courier_id != approved_courier {
-                if self.proposals.get(&courier_id).unwrap().remove(&order_id).is_some() {
-                    if let Some(removed_id) = order.2.pop() {
+                let proposal_storage = env::storage_usage();
+                let mut courier_proposals = self.proposals.get(&courier_id).ok_or(InternalError::NotFound)?;
+                if let Some(proposal) = courier_proposals.remove(&order_id).map(Proposal::from) {
+                    self.proposals.insert(&courier_id, &courier_proposals);
+                    proposal_bytes_freed += proposal_storage.saturating_sub(env::storage_usage());
+                    let payer_id = if proposal.eta_minutes.is_some() { &courier_id } else { &id };
+                    self.settle_storage(payer_id, proposal_storage);
+                    if let Some(removed_id) = couriers.pop() {
                         courier_ids.push(removed_id);
                     }
                 }
             } else {
-                order.2.pop();
+                couriers.pop();
             }
             page -= 1;
         }
+        my_orders.insert(&order_id, &(order.into(), items, couriers));
+        self.settle_storage(&id, initial_storage - proposal_bytes_freed);
 
-        courier_ids
+        Ok(courier_ids)
     }
 
-    /*pub fn get_order(&self, order_id: String, account_id: Option<String>) -> Option<UserOrder> {
+    /*pub fn get_order(&self, order_id: String, account_id: Option<AccountId>) -> Option<UserOrder> {
         if let Some(id) = account_id {
             return self.retrieve_order(id, order_id)
         }
         let signer = env::predecessor_account_id();
-        let id: String = String::from_utf8(near_sdk::env::sha256(signer.as_bytes()).to_vec()).unwrap();
-        return self.retrieve_order(id, order_id)
+        return self.retrieve_order(signer, order_id)
     } */
 
     pub fn get_pending_orders(&self, page: u16, limit: u16) -> Option<OrderBundle> {
-        let account_id = env::predecessor_account_id();
-        let id: String = String::from_utf8(near_sdk::env::sha256(account_id.as_bytes()).to_vec()).unwrap();
+        let id = env::predecessor_account_id();
         if let Some(pending_orders) = self.orders_pending.get(&id) {
             let mut orders: Vec<UserOrder> = vec![];
             pending_orders.keys().skip(page.into()).take(limit.into()).for_each(|order_id| {
@@ -179,7 +262,7 @@ impl Contract {
                     orders.push(user_order);
                 }
             });
-            let next_page = page + limit;
+            let next_page = page.saturating_add(limit);
             let bundled_order = OrderBundle {
                 next_page,
                 orders
@@ -189,42 +272,124 @@ impl Contract {
         None
     }
 
-    pub fn get_staged_orders(&self, page: u16, limit: u16/*, account_id: Option<String> */) -> Option<OrderBundle> {
+    pub fn get_staged_orders(&self, page: u16, limit: u16/*, account_id: Option<AccountId> */) -> Option<OrderBundle> {
         /* if let Some(id) = account_id {
             return self.retrieve_staged_order(id, page, limit)
         } */
         let signer = env::predecessor_account_id();
-        let id: String = String::from_utf8(near_sdk::env::sha256(signer.as_bytes()).to_vec()).unwrap();
-        return self.retrieve_staged_order(id, page, limit)
+        return self.retrieve_indexed_orders(&self.orders_staged, signer, page, limit)
+    }
+
+    pub fn get_shipping_orders(&self, page: u16, limit: u16) -> Option<OrderBundle> {
+        let signer = env::predecessor_account_id();
+        return self.retrieve_indexed_orders(&self.orders_shipping, signer, page, limit)
     }
 
-    pub fn stage_order(&self, order_id: String) -> Option<String> {
+    #[handle_result]
+    pub fn stage_order(&mut self, order_id: String) -> Result<String, InternalError> {
+        let id = env::predecessor_account_id();
+        let initial_storage = env::storage_usage();
+        let buyer_id = self.orders_pending.get(&id).and_then(|pending_orders| pending_orders.get(&order_id)).ok_or(InternalError::NotFound)?;
+        self.transition_order(&buyer_id, &order_id, OrderStatus::STAGGED)?;
+        self.charge_storage(&id, initial_storage)?;
+        DTradeEvent::StageOrder(vec![StageOrderData {
+            order_id: &order_id,
+            buyer_id: &buyer_id,
+            seller_id: &id,
+        }]).emit();
+        Ok(order_id)
+    }
+
+    #[handle_result]
+    pub fn ship_order(&mut self, order_id: String) -> Result<String, InternalError> {
+        let id = env::predecessor_account_id();
+        let initial_storage = env::storage_usage();
+        let buyer_id = self.orders_staged.get(&id).and_then(|staged_orders| staged_orders.get(&order_id)).ok_or(InternalError::NotFound)?;
+        let (order, _, _) = self.orders.get(&buyer_id).and_then(|my_orders| my_orders.get(&order_id)).ok_or(InternalError::NotFound)?;
+        if Order::from(order).courier.is_none() {
+            return Err(InternalError::CourierNotAssigned)
+        }
+        self.transition_order(&buyer_id, &order_id, OrderStatus::SHIPPING)?;
+        // The buyer now gets the confirmation window to confirm delivery or dispute
+        self.extend_lock(&buyer_id, &order_id, env::block_timestamp_ms() + self.config.confirmation_window_ms)?;
+        self.charge_storage(&id, initial_storage)?;
+        Ok(order_id)
+    }
+
+    #[handle_result]
+    pub fn confirm_delivery(&mut self, order_id: String) -> Result<AccountId, InternalError> {
         let account_id = env::predecessor_account_id();
-        let id: String = String::from_utf8(near_sdk::env::sha256(account_id.as_bytes()).to_vec()).unwrap();
-        if let Some(pending_orders) = self.orders_pending.get(&id) {
-            if let Some(buyer_id) = pending_orders.get(&order_id) {
-                if let Some(_order) = self.orders.get(&buyer_id).unwrap().get(&order_id) {
-                    let mut order = _order.0;
-                    order.status = OrderStatus::STAGGED;
-                    let updated_order = (order, _order.1, _order.2);
-                    self.orders.get(&buyer_id).unwrap().insert(&order_id, &updated_order);
-                    return Some(order_id)
-                }
-            }
+        let initial_storage = env::storage_usage();
+        let order = self.transition_order(&account_id, &order_id, OrderStatus::DELIVERED)?;
+        let seller_id = self.release(&account_id, &order_id, order.courier)?;
+        self.settle_storage(&account_id, initial_storage);
+        Ok(seller_id)
+    }
+
+    // The courier proves the handoff by revealing the delivery code the buyer committed to when
+    // placing the order, which settles it as if the buyer had confirmed delivery. The commitment
+    // is public, so the code has to be a base58 encoded random value of at least 128 bits; shorter
+    // codes could be guessed offline and are never accepted.
+    #[handle_result]
+    pub fn deliver(&mut self, order_id: String, code: String) -> Result<AccountId, InternalError> {
+        let courier_id = env::predecessor_account_id();
+        let initial_storage = env::storage_usage();
+        let buyer_id = self.order_buyers.get(&order_id).ok_or(InternalError::NotFound)?;
+        let (order, _, _) = self.orders.get(&buyer_id).and_then(|my_orders| my_orders.get(&order_id)).ok_or(InternalError::NotFound)?;
+        if Order::from(order).courier.as_ref() != Some(&courier_id) {
+            return Err(InternalError::Unauthorized)
         }
-        None
+        let code = near_sdk::bs58::decode(&code).into_vec().ok().filter(|code| code.len() >= MIN_DELIVERY_CODE_BYTES)
+            .ok_or_else(|| InternalError::InvalidPayload(format!("delivery code must be at least {} base58 encoded bytes", MIN_DELIVERY_CODE_BYTES)))?;
+        let code_hash = self.delivery_codes.get(&order_id).ok_or(InternalError::NotFound)?;
+        if env::sha256(&code) != code_hash {
+            return Err(InternalError::Unauthorized)
+        }
+        let order = self.transition_order(&buyer_id, &order_id, OrderStatus::DELIVERED)?;
+        let seller_id = self.release(&buyer_id, &order_id, order.courier)?;
+        self.settle_storage(&buyer_id, initial_storage);
+        Ok(seller_id)
     }
 
-    pub fn register_courier(&mut self, profile: CourierUser) {
+    // Sellers may cancel any order they have not shipped. A buyer may cancel a pending order
+    // outright; once it is staged the request is only flagged on the order, and the seller either
+    // consents by calling `cancel_order` as well or declines by shipping it, which clears the flag.
+    // Cancelled orders are refunded in full.
+    #[handle_result]
+    pub fn cancel_order(&mut self, order_id: String) -> Result<Option<AccountId>, InternalError> {
+        let id = env::predecessor_account_id();
+        let initial_storage = env::storage_usage();
+        let seller_order = self.orders_pending.get(&id).and_then(|pending_orders| pending_orders.get(&order_id))
+            .or_else(|| self.orders_staged.get(&id).and_then(|staged_orders| staged_orders.get(&order_id)));
+        if let Some(buyer_id) = seller_order {
+            let seller_id = self.cancel_and_refund(&buyer_id, order_id)?;
+            self.settle_storage(&buyer_id, initial_storage);
+            return Ok(Some(seller_id))
+        }
+
+        let mut my_orders = self.orders.get(&id).ok_or(InternalError::NotFound)?;
+        let (order, items, couriers) = my_orders.get(&order_id).ok_or(InternalError::NotFound)?;
+        let mut order = Order::from(order);
+        match order.status {
+            OrderStatus::PENDING => {
+                let seller_id = self.cancel_and_refund(&id, order_id)?;
+                self.settle_storage(&id, initial_storage);
+                Ok(Some(seller_id))
+            },
+            OrderStatus::STAGGED => {
+                order.cancel_requested = true;
+                my_orders.insert(&order_id, &(order.into(), items, couriers));
+                Ok(None)
+            },
+            _ => Err(InternalError::InvalidTransition)
+        }
+    }
+
+    #[handle_result]
+    pub fn register_courier(&mut self, profile: CourierUser) -> Result<(), InternalError> {
         let account_id = env::predecessor_account_id();
-        let vehicle = match profile.vehicle.as_str() {
-            "motorcycle" => Vehicle::MOTORCYCLE,
-            "tuktuk" => Vehicle::TUKTUK,
-            "car" => Vehicle::CAR,
-            "pickup" => Vehicle::PICKUP,
-            "lorry" => Vehicle::LORRY,
-            _ => env::panic_str("Invalid vehicle record!")
-        };
+        let initial_storage = env::storage_usage();
+        let vehicle = profile.vehicle.parse::<Vehicle>()?;
 
         let courier = Courier {
             vehicle,
@@ -240,13 +405,19 @@ impl Contract {
             image: profile.image,
             courier_profile: Some(courier)
         };
-        let id: String = String::from_utf8(near_sdk::env::sha256(account_id.as_bytes()).to_vec()).unwrap();
-        self.couriers.insert(&id, &user);
+        DTradeEvent::RegisterCourier(vec![RegisterCourierData {
+            courier_id: &account_id,
+            vehicle: &user.courier_profile.as_ref().unwrap().vehicle,
+        }]).emit();
+        self.couriers.insert(&account_id, &user.into());
+        self.charge_storage(&account_id, initial_storage)?;
+        Ok(())
     }
 
-    pub fn save_company(&mut self, company_id: String) {
-        let account_id = env::predecessor_account_id();
-        let id: String = String::from_utf8(near_sdk::env::sha256(account_id.as_bytes()).to_vec()).unwrap();
+    #[handle_result]
+    pub fn save_company(&mut self, company_id: AccountId) -> Result<(), InternalError> {
+        let id = env::predecessor_account_id();
+        let initial_storage = env::storage_usage();
         if let Some(_company) = self.companies.get(&company_id) {
             let mut company_couriers = self.couriers_by_company.get(&company_id).unwrap_or_else(|| {
                 let prefix: Vec<u8> = [
@@ -257,6 +428,7 @@ impl Contract {
                 UnorderedMap::new(prefix)
             });
             company_couriers.insert(&id, &0);
+            self.couriers_by_company.insert(&company_id, &company_couriers);
             let mut courier_companies = self.courier_companies.get(&id).unwrap_or_else(|| {
                 let prefix: Vec<u8> = [
                         b"m".as_slice(),
@@ -266,14 +438,20 @@ impl Contract {
                 Vector::new(prefix)
             });
             courier_companies.push(&company_id);
+            self.courier_companies.insert(&id, &courier_companies);
+            self.charge_storage(&id, initial_storage)?;
+            DTradeEvent::SaveCompany(vec![SaveCompanyData {
+                courier_id: &id,
+                company_id: &company_id,
+            }]).emit();
+            Ok(())
         } else {
-            env::panic_str("Company does not exist!");
+            Err(InternalError::UnknownCompany)
         }
     }
 
     pub fn courier_saved_companies(&self, page: u8, limit: u8) -> Option<Vec<Company>> {
-        let account_id = env::predecessor_account_id();
-        let id: String = String::from_utf8(near_sdk::env::sha256(account_id.as_bytes()).to_vec()).unwrap();
+        let id = env::predecessor_account_id();
         let mut companies: Vec<Company> = vec![];
         if let Some(list_of_companies) = self.courier_companies.get(&id) {
             list_of_companies.iter().skip(page.into()).take(limit.into()).for_each(|company_id| {
@@ -285,12 +463,12 @@ impl Contract {
         Some(companies)
     }
 
-    pub fn company_couriers(&self, company_id: String, page: u8, limit: u8) -> Option<Vec<CourierProfile>> {
+    pub fn company_couriers(&self, company_id: AccountId, page: u8, limit: u8) -> Option<Vec<CourierProfile>> {
         let mut couriers: Vec<CourierProfile> = vec![];
         if let Some(_couriers) = self.couriers_by_company.get(&company_id) {
             _couriers.keys().skip(page.into()).take(limit.into()).for_each(|courier_id| {
                 if let Some(number_of_deliveries) = _couriers.get(&courier_id) {
-                    let courier = self.couriers.get(&courier_id).unwrap();
+                    let courier = self.couriers.get(&courier_id).map(User::from).unwrap();
                     let courier_profile = CourierProfile {
                         deliveries: number_of_deliveries,
                         profile: courier
@@ -302,15 +480,19 @@ impl Contract {
         Some(couriers)
     }
 
-    pub fn place_proposal(&self, courier_id: String, order_id: String) {
-        let account_id = env::predecessor_account_id();
-        let id: String = String::from_utf8(near_sdk::env::sha256(account_id.as_bytes()).to_vec()).unwrap();
+    #[handle_result]
+    pub fn place_proposal(&mut self, courier_id: AccountId, order_id: String) -> Result<(), InternalError> {
+        let id = env::predecessor_account_id();
+        let initial_storage = env::storage_usage();
+        let mut my_orders = self.orders.get(&id).ok_or(InternalError::NotFound)?;
+        let mut order = my_orders.get(&order_id).ok_or(InternalError::NotFound)?;
         let proposal = Proposal {
             client: id.clone(),
-            courier_id: None,
+            courier_id: Some(courier_id.clone()),
             amount: 0,
             fee: 0,
-            status: ProposalStatus::PENDING
+            status: ProposalStatus::PENDING,
+            eta_minutes: None
         };
         let mut courier_proposals = self.proposals.get(&courier_id).unwrap_or_else(|| {
             let prefix: Vec<u8> = [
@@ -320,23 +502,25 @@ impl Contract {
                 .concat();
             UnorderedMap::new(prefix)
         });
-        courier_proposals.insert(&order_id, &proposal);
-        let mut order = self.orders.get(&id).unwrap().get(&order_id).unwrap();
+        courier_proposals.insert(&order_id, &proposal.into());
+        self.proposals.insert(&courier_id, &courier_proposals);
         order.2.push(&courier_id);
+        my_orders.insert(&order_id, &order);
+        self.charge_storage(&id, initial_storage)?;
+        Ok(())
     }
 
     pub fn get_proposals(&self, page: u16, limit: u16) -> Option<OrderBundle> {
-        let account_id = env::predecessor_account_id();
-        let id = self.get_hash(account_id);
+        let id = env::predecessor_account_id();
         if let Some(order_proposals) = self.proposals.get(&id) {
             let mut orders: Vec<UserOrder> = vec![];
             order_proposals.keys().skip(page.into()).take(limit.into()).for_each(|order_id| {
-                let proposal = order_proposals.get(&order_id).unwrap();
+                let proposal = order_proposals.get(&order_id).map(Proposal::from).unwrap();
                 if let Some(user_order) = self.retrieve_order(proposal.client, order_id) {
                     orders.push(user_order);
                 }
             });
-            let next_page = page + limit;
+            let next_page = page.saturating_add(limit);
             let order_bundle = OrderBundle {
                 next_page,
                 orders
@@ -346,29 +530,29 @@ impl Contract {
         None
     }
 
-    pub fn suggest_shipping_fee(&self, order_id: String, amount: u128) {
-        let account_id = env::predecessor_account_id();
-        let id = self.get_hash(account_id);
-        if let Some(proposal) = self.proposals.get(&id).unwrap().get(&order_id) {
-            let new_proposal = Proposal {
-                fee: amount,
-                ..proposal
-            };
-            self.proposals.get(&id).unwrap().insert(&order_id, &new_proposal);
+    #[handle_result]
+    pub fn suggest_shipping_fee(&mut self, order_id: String, amount: u128) -> Result<(), InternalError> {
+        let id = env::predecessor_account_id();
+        let mut courier_proposals = self.proposals.get(&id).ok_or(InternalError::NotFound)?;
+        let proposal = courier_proposals.get(&order_id).map(Proposal::from).ok_or(InternalError::NotFound)?;
+        if proposal.status == ProposalStatus::APPROVED {
+            return Err(InternalError::InvalidTransition)
         }
+        let new_proposal = Proposal {
+            fee: amount,
+            status: ProposalStatus::PROPOSED,
+            ..proposal
+        };
+        courier_proposals.insert(&order_id, &new_proposal.into());
+        Ok(())
     }
 
-    fn get_hash(&self, value: AccountId) -> String {
-        let hash: String = String::from_utf8(near_sdk::env::sha256(value.as_bytes()).to_vec()).unwrap();
-        hash
-    }
-
-    fn retrieve_order(&self, id: String, order_id: String) -> Option<UserOrder> {
-        if let Some(order) = self.orders.get(&id).unwrap().get(&order_id) {
+    fn retrieve_order(&self, id: AccountId, order_id: String) -> Option<UserOrder> {
+        if let Some(order) = self.orders.get(&id).and_then(|my_orders| my_orders.get(&order_id)) {
             let _products = order.1.iter().collect::<Vec<OrderItem>>();
             let user_order = UserOrder {
                 id: order_id,
-                metadata: order.0,
+                metadata: order.0.into(),
                 products: _products,
             };
             return Some(user_order)
@@ -376,16 +560,16 @@ impl Contract {
         return None
     }
 
-    fn retrieve_staged_order(&self, id: String, page: u16, limit: u16) -> Option<OrderBundle> {
-        if let Some(stagged_orders) = self.orders_staged.get(&id) {
+    fn retrieve_indexed_orders(&self, index: &SellerOrders, id: AccountId, page: u16, limit: u16) -> Option<OrderBundle> {
+        if let Some(indexed_orders) = index.get(&id) {
             let mut orders: Vec<UserOrder> = vec![];
-            stagged_orders.keys().skip(page.into()).take(limit.into()).for_each(|order_id| {
-                let buyer_id = stagged_orders.get(&order_id).unwrap();
+            indexed_orders.keys().skip(page.into()).take(limit.into()).for_each(|order_id| {
+                let buyer_id = indexed_orders.get(&order_id).unwrap();
                 if let Some(user_order) = self.retrieve_order(buyer_id, order_id) {
                     orders.push(user_order);
                 }
             });
-            let next_page = page + limit;
+            let next_page = page.saturating_add(limit);
             let bundled_order = OrderBundle {
                 next_page,
                 orders
@@ -394,6 +578,83 @@ impl Contract {
         }
         None
     }
+
+    // Moves an order to `next`, rejecting illegal transitions and keeping the seller's
+    // pending/staged/shipping indexes in sync with the order status. A disputed order
+    // can only be moved by resolving the dispute, and one with a pending insurance claim
+    // cannot be delivered.
+    fn transition_order(&mut self, buyer_id: &AccountId, order_id: &String, next: OrderStatus) -> Result<Order, InternalError> {
+        let mut my_orders = self.orders.get(buyer_id).ok_or(InternalError::NotFound)?;
+        let (order, items, couriers) = my_orders.get(order_id).ok_or(InternalError::NotFound)?;
+        let mut order = Order::from(order);
+        if !order.status.can_transition_to(&next) {
+            return Err(InternalError::InvalidTransition)
+        }
+        if !matches!(next, OrderStatus::RESOLVED) && self.is_disputed(order_id) {
+            return Err(InternalError::Disputed)
+        }
+        if matches!(next, OrderStatus::DELIVERED) && self.is_claim_pending(order_id) {
+            return Err(InternalError::ClaimPending)
+        }
+        // Staged orders still take bids, anything further along is off the board
+        if !matches!(next, OrderStatus::STAGGED) {
+            self.delist_open_order(order_id);
+        }
+        // Settled orders have no use for their delivery code
+        if !matches!(next, OrderStatus::STAGGED | OrderStatus::SHIPPING) {
+            self.delivery_codes.remove(order_id);
+        }
+
+        if let Some((index, _)) = self.status_index(&order.status) {
+            if let Some(mut seller_orders) = index.get(&order.seller) {
+                seller_orders.remove(order_id);
+                index.insert(&order.seller, &seller_orders);
+            }
+        }
+        if let Some((index, prefix)) = self.status_index(&next) {
+            let mut seller_orders = index.get(&order.seller).unwrap_or_else(|| {
+                let prefix: Vec<u8> = [
+                        prefix,
+                        &near_sdk::env::sha256_array(order.seller.as_bytes()),
+                    ]
+                    .concat();
+                UnorderedMap::new(prefix)
+            });
+            seller_orders.insert(order_id, buyer_id);
+            index.insert(&order.seller, &seller_orders);
+        }
+
+        // Couriers are in transit for as long as any order they carry is shipping
+        if let Some(courier_id) = order.courier.as_ref() {
+            if matches!(next, OrderStatus::SHIPPING) {
+                self.track_shipment(courier_id, true);
+            } else if matches!(order.status, OrderStatus::SHIPPING) {
+                self.track_shipment(courier_id, false);
+            }
+        }
+
+        order.status = next;
+        // A buyer's cancellation request only stands while the order is staged
+        order.cancel_requested = false;
+        let updated_order = (VersionedOrder::from(order), items, couriers);
+        my_orders.insert(order_id, &updated_order);
+        Ok(updated_order.0.into())
+    }
+
+    fn cancel_and_refund(&mut self, buyer_id: &AccountId, order_id: String) -> Result<AccountId, InternalError> {
+        let order = self.transition_order(buyer_id, &order_id, OrderStatus::CANCELLED)?;
+        self.refund_premium(buyer_id, &order);
+        self.refund_escrow(buyer_id.clone(), order_id)
+    }
+
+    fn status_index(&mut self, status: &OrderStatus) -> Option<(&mut SellerOrders, &'static [u8])> {
+        match status {
+            OrderStatus::PENDING => Some((&mut self.orders_pending, b"f")),
+            OrderStatus::STAGGED => Some((&mut self.orders_staged, b"t")),
+            OrderStatus::SHIPPING => Some((&mut self.orders_shipping, b"u")),
+            _ => None
+        }
+    }
 }
 
 /*
@@ -402,5 +663,468 @@ impl Contract {
  */
 #[cfg(test)]
 mod tests {
-    
+    use super::*;
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::{serde_json::json, testing_env, ONE_NEAR};
+    use utils::{ClaimReason, CompanyDetails, Coordinate, CourierUser};
+
+    const DAY_MS: u64 = 24 * 60 * 60 * 1000;
+    const ORDER_TOTAL: u128 = 10_000_000;
+    const SHIPPING_FEE: u128 = 1_000_000;
+
+    fn token() -> AccountId {
+        "usdc.near".parse().unwrap()
+    }
+
+    fn config() -> Config {
+        Config {
+            accepted_tokens: vec![(token(), 6)],
+            platform_fee_bps: 100,
+            dispute_window_ms: 7 * DAY_MS,
+            arbitration_timeout_ms: 3 * DAY_MS,
+            shipping_timeout_ms: 5 * DAY_MS,
+            bidding_window_ms: DAY_MS,
+            confirmation_window_ms: 2 * DAY_MS,
+            upgrade_delay_ms: DAY_MS,
+        }
+    }
+
+    fn set_caller(context: &mut VMContextBuilder, account_id: AccountId, deposit: u128) {
+        testing_env!(context.predecessor_account_id(account_id).attached_deposit(deposit).build());
+    }
+
+    // Owner is accounts(0), seller accounts(1), buyer accounts(2), stranger accounts(3),
+    // arbitrator accounts(4) and courier accounts(5). Returns the id of a pending order between buyer and seller.
+    fn setup() -> (VMContextBuilder, Contract, String) {
+        let mut context = VMContextBuilder::new();
+        set_caller(&mut context, accounts(0), 0);
+        let mut contract = Contract::new(accounts(0), config());
+        contract.add_arbitrator(accounts(4)).unwrap();
+
+        for account_id in [accounts(1), accounts(2)] {
+            set_caller(&mut context, account_id, ONE_NEAR);
+            contract.storage_deposit(None, None).unwrap();
+        }
+        set_caller(&mut context, accounts(1), 0);
+        contract.register_company(CompanyDetails {
+            name: "Shop".to_string(),
+            wallet: accounts(1),
+            phone: "0700000000".to_string(),
+            email: "shop@example.com".to_string(),
+            location: Coordinate { lat: 0, lon: 0 },
+        }).unwrap();
+
+        let order_id = place_order(&mut context, &mut contract, 0, None);
+        (context, contract, order_id)
+    }
+
+    // The buyer orders a single item worth `ORDER_TOTAL` from the seller and pays the premium on top.
+    fn place_order(context: &mut VMContextBuilder, contract: &mut Contract, percentage_insurance: u8, delivery_code_hash: Option<String>) -> String {
+        let cart = json!({
+            "seller": accounts(1),
+            "location": { "lat": 0, "lon": 0 },
+            "percentage_insurance": percentage_insurance,
+            "list_for_bidding": false,
+            "items": [{ "name": "Phone", "serial": "SN-1", "price": ORDER_TOTAL.to_string(), "quantity": 1, "reference": "ref-1" }],
+            "delivery_code_hash": delivery_code_hash
+        });
+        let total = ORDER_TOTAL + ORDER_TOTAL / 100 * percentage_insurance as u128;
+        submit_cart(context, contract, cart, total)
+    }
+
+    // Like `place_order`, but the order is listed for couriers to bid on and delivered to `location`.
+    fn list_order(context: &mut VMContextBuilder, contract: &mut Contract, location: Coordinate) -> String {
+        let cart = json!({
+            "seller": accounts(1),
+            "location": location,
+            "percentage_insurance": 0,
+            "list_for_bidding": true,
+            "items": [{ "name": "Phone", "serial": "SN-1", "price": ORDER_TOTAL.to_string(), "quantity": 1, "reference": "ref-1" }]
+        });
+        submit_cart(context, contract, cart, ORDER_TOTAL)
+    }
+
+    fn submit_cart(context: &mut VMContextBuilder, contract: &mut Contract, cart: near_sdk::serde_json::Value, total: u128) -> String {
+        set_caller(context, token(), 0);
+        let order_count = contract.orders.get(&accounts(2)).map(|my_orders| my_orders.len()).unwrap_or(0);
+        let unused = contract.ft_on_transfer(accounts(2), U128(total), format!("place_order|{}", cart));
+        assert_eq!(unused, "0");
+        contract.orders.get(&accounts(2)).unwrap().keys().nth(order_count as usize).unwrap()
+    }
+
+    // The buyer asks the courier for a quote and pays twice the proposed fee to approve it.
+    fn approve_courier(context: &mut VMContextBuilder, contract: &mut Contract, order_id: &String) {
+        set_caller(context, accounts(2), 0);
+        contract.place_proposal(accounts(5), order_id.clone()).unwrap();
+        set_caller(context, accounts(5), 0);
+        contract.suggest_shipping_fee(order_id.clone(), SHIPPING_FEE).unwrap();
+        let approval = json!({ "order_id": order_id, "courier_id": accounts(5) });
+        set_caller(context, token(), 0);
+        let unused = contract.ft_on_transfer(accounts(2), U128(2 * SHIPPING_FEE), format!("approve_proposal|{}", approval));
+        assert_eq!(unused, "0");
+    }
+
+    #[test]
+    fn refund_rejects_buyer() {
+        let (mut context, mut contract, order_id) = setup();
+        set_caller(&mut context, accounts(2), 0);
+        assert!(matches!(contract.refund(accounts(2), order_id), Err(InternalError::Unauthorized)));
+    }
+
+    #[test]
+    fn refund_rejects_stranger() {
+        let (mut context, mut contract, order_id) = setup();
+        set_caller(&mut context, accounts(3), 0);
+        assert!(matches!(contract.refund(accounts(2), order_id), Err(InternalError::Unauthorized)));
+        assert_eq!(contract.get_balance(accounts(2), token()), U128(0));
+    }
+
+    #[test]
+    fn refund_by_seller_returns_escrow_to_buyer() {
+        let (mut context, mut contract, order_id) = setup();
+        set_caller(&mut context, accounts(1), 0);
+        contract.refund(accounts(2), order_id.clone()).unwrap();
+        assert_eq!(contract.get_balance(accounts(2), token()), U128(ORDER_TOTAL));
+        assert!(contract.get_locked_balance(accounts(2), order_id).is_none());
+    }
+
+    #[test]
+    fn refund_by_arbitrator_returns_escrow_to_buyer() {
+        let (mut context, mut contract, order_id) = setup();
+        set_caller(&mut context, accounts(4), 0);
+        contract.refund(accounts(2), order_id).unwrap();
+        assert_eq!(contract.get_balance(accounts(2), token()), U128(ORDER_TOTAL));
+    }
+
+    #[test]
+    fn refund_of_disputed_order_waits_for_arbitration_timeout() {
+        let (mut context, mut contract, order_id) = setup();
+        set_caller(&mut context, accounts(2), 0);
+        contract.open_dispute(order_id.clone(), "never shipped".to_string()).unwrap();
+
+        for account_id in [accounts(1), accounts(3)] {
+            set_caller(&mut context, account_id, 0);
+            assert!(matches!(contract.refund(accounts(2), order_id.clone()), Err(InternalError::Unauthorized)));
+        }
+
+        context.block_timestamp((3 * DAY_MS + 1) * 1_000_000);
+        set_caller(&mut context, accounts(3), 0);
+        contract.refund(accounts(2), order_id).unwrap();
+        assert_eq!(contract.get_balance(accounts(2), token()), U128(ORDER_TOTAL));
+    }
+
+    #[test]
+    fn cancel_after_courier_approval_refunds_courier_fee() {
+        let (mut context, mut contract, order_id) = setup();
+        approve_courier(&mut context, &mut contract, &order_id);
+        set_caller(&mut context, accounts(2), 0);
+        contract.cancel_order(order_id).unwrap();
+        assert_eq!(contract.get_balance(accounts(2), token()), U128(ORDER_TOTAL + 2 * SHIPPING_FEE));
+        assert_eq!(contract.get_balance(accounts(5), token()), U128(0));
+    }
+
+    #[test]
+    fn claim_expired_of_unshipped_order_refunds_courier_fee() {
+        let (mut context, mut contract, order_id) = setup();
+        approve_courier(&mut context, &mut contract, &order_id);
+        context.block_timestamp((5 * DAY_MS + 1) * 1_000_000);
+        set_caller(&mut context, accounts(3), 0);
+        contract.claim_expired(order_id).unwrap();
+        assert_eq!(contract.get_balance(accounts(2), token()), U128(ORDER_TOTAL + 2 * SHIPPING_FEE));
+    }
+
+    #[test]
+    fn confirmed_delivery_pays_courier_fee() {
+        let (mut context, mut contract, order_id) = setup();
+        approve_courier(&mut context, &mut contract, &order_id);
+        set_caller(&mut context, accounts(1), 0);
+        contract.stage_order(order_id.clone()).unwrap();
+        contract.ship_order(order_id.clone()).unwrap();
+        set_caller(&mut context, accounts(2), 0);
+        contract.confirm_delivery(order_id).unwrap();
+        // Both payouts are charged the 1% platform fee
+        assert_eq!(contract.get_balance(accounts(5), token()), U128(2 * SHIPPING_FEE / 100 * 99));
+        assert_eq!(contract.get_balance(accounts(1), token()), U128(ORDER_TOTAL / 100 * 99));
+    }
+
+    #[test]
+    fn settled_order_credits_storage_back_to_buyer() {
+        let (mut context, mut contract, order_id) = setup();
+        let before = contract.storage_balance_of(accounts(2)).unwrap().available.0;
+        set_caller(&mut context, accounts(1), 0);
+        contract.cancel_order(order_id).unwrap();
+        assert!(contract.storage_balance_of(accounts(2)).unwrap().available.0 > before);
+    }
+
+    #[test]
+    fn resolved_dispute_splits_courier_fee_of_shipped_order() {
+        let (mut context, mut contract, order_id) = setup();
+        approve_courier(&mut context, &mut contract, &order_id);
+        set_caller(&mut context, accounts(1), 0);
+        contract.stage_order(order_id.clone()).unwrap();
+        contract.ship_order(order_id.clone()).unwrap();
+        set_caller(&mut context, accounts(2), 0);
+        contract.open_dispute(order_id.clone(), "half of it arrived".to_string()).unwrap();
+        set_caller(&mut context, accounts(4), 0);
+        contract.resolve_dispute(order_id, 5_000).unwrap();
+        assert_eq!(contract.get_balance(accounts(2), token()), U128(ORDER_TOTAL / 2 + SHIPPING_FEE));
+        assert_eq!(contract.get_balance(accounts(5), token()), U128(SHIPPING_FEE / 100 * 99));
+        assert_eq!(contract.get_balance(accounts(1), token()), U128(ORDER_TOTAL / 2 / 100 * 99));
+    }
+
+    #[test]
+    fn resolved_dispute_of_unshipped_order_refunds_premium() {
+        let (mut context, mut contract, _) = setup();
+        let order_id = place_order(&mut context, &mut contract, 10, None);
+        set_caller(&mut context, accounts(2), 0);
+        contract.open_dispute(order_id.clone(), "never staged".to_string()).unwrap();
+        set_caller(&mut context, accounts(4), 0);
+        contract.resolve_dispute(order_id, 10_000).unwrap();
+        assert_eq!(contract.get_balance(accounts(2), token()), U128(11_000_000));
+    }
+
+    #[test]
+    fn open_orders_near_page_through_every_order() {
+        let (mut context, mut contract, _) = setup();
+        let mut listed: Vec<String> = (0..3).map(|_| list_order(&mut context, &mut contract, Coordinate { lat: 0, lon: 0 })).collect();
+        let first = contract.get_open_orders_near(0, 0, 10, None, 0, 2).unwrap();
+        assert_eq!((first.orders.len(), first.next_page), (2, 2));
+        let second = contract.get_open_orders_near(0, 0, 10, None, first.next_page, 2).unwrap();
+        assert_eq!((second.orders.len(), second.next_page), (1, 3));
+        let mut found: Vec<String> = first.orders.into_iter().chain(second.orders).map(|view| view.order.id).collect();
+        found.sort();
+        listed.sort();
+        assert_eq!(found, listed);
+    }
+
+    #[test]
+    fn open_orders_near_leave_out_pickups_beyond_radius() {
+        let (mut context, mut contract, _) = setup();
+        let order_id = list_order(&mut context, &mut contract, Coordinate { lat: 0, lon: 0 });
+        // The seller's company is about 56km west of the courier
+        assert!(contract.get_open_orders_near(0, 500_000, 50, None, 0, 10).unwrap().orders.is_empty());
+        let orders = contract.get_open_orders_near(0, 500_000, 60, None, 0, 10).unwrap().orders;
+        assert_eq!(orders.len(), 1);
+        assert_eq!(orders[0].order.id, order_id);
+    }
+
+    #[test]
+    fn open_orders_near_leave_out_drop_offs_beyond_vehicle_range() {
+        let (mut context, mut contract, _) = setup();
+        // About 33km from the pickup: in range of a motorcycle but not of a tuktuk
+        list_order(&mut context, &mut contract, Coordinate { lat: 0, lon: 300_000 });
+        assert!(contract.get_open_orders_near(0, 0, 10, Some("tuktuk".to_string()), 0, 10).unwrap().orders.is_empty());
+        assert_eq!(contract.get_open_orders_near(0, 0, 10, Some("motorcycle".to_string()), 0, 10).unwrap().orders.len(), 1);
+        assert!(matches!(contract.get_open_orders_near(0, 0, 10, Some("MOTORCYCLE".to_string()), 0, 10), Err(InternalError::InvalidPayload(_))));
+    }
+
+    fn register_courier(context: &mut VMContextBuilder, contract: &mut Contract) {
+        set_caller(context, accounts(5), ONE_NEAR);
+        contract.storage_deposit(None, None).unwrap();
+        set_caller(context, accounts(5), 0);
+        contract.register_courier(CourierUser {
+            name: "Rider".to_string(),
+            phone: "0711111111".to_string(),
+            email: "rider@example.com".to_string(),
+            image: "".to_string(),
+            vehicle: "motorcycle".to_string(),
+            make_model: "Boxer".to_string(),
+            plate_id: "KMA 001A".to_string(),
+        }).unwrap();
+    }
+
+    #[test]
+    fn place_bid_after_deadline_is_expired() {
+        let (mut context, mut contract, _) = setup();
+        let order_id = list_order(&mut context, &mut contract, Coordinate { lat: 0, lon: 0 });
+        register_courier(&mut context, &mut contract);
+        context.block_timestamp((DAY_MS + 1) * 1_000_000);
+        set_caller(&mut context, accounts(5), 0);
+        assert!(matches!(contract.place_bid(order_id, U128(SHIPPING_FEE), 30), Err(InternalError::Expired)));
+    }
+
+    #[test]
+    fn place_bid_rejects_unregistered_courier() {
+        let (mut context, mut contract, _) = setup();
+        let order_id = list_order(&mut context, &mut contract, Coordinate { lat: 0, lon: 0 });
+        set_caller(&mut context, accounts(3), 0);
+        assert!(matches!(contract.place_bid(order_id, U128(SHIPPING_FEE), 30), Err(InternalError::Unauthorized)));
+    }
+
+    #[test]
+    fn bidding_again_updates_the_bid() {
+        let (mut context, mut contract, _) = setup();
+        let order_id = list_order(&mut context, &mut contract, Coordinate { lat: 0, lon: 0 });
+        register_courier(&mut context, &mut contract);
+        set_caller(&mut context, accounts(5), 0);
+        contract.place_bid(order_id.clone(), U128(SHIPPING_FEE), 30).unwrap();
+        contract.place_bid(order_id.clone(), U128(SHIPPING_FEE / 2), 45).unwrap();
+        let (_, _, couriers) = contract.orders.get(&accounts(2)).unwrap().get(&order_id).unwrap();
+        assert_eq!(couriers.to_vec(), vec![accounts(5)]);
+        let proposal = contract.proposals.get(&accounts(5)).unwrap().get(&order_id).map(Proposal::from).unwrap();
+        assert_eq!((proposal.fee, proposal.eta_minutes), (SHIPPING_FEE / 2, Some(45)));
+    }
+
+    #[test]
+    fn withdraw_bid_after_approval_is_rejected() {
+        let (mut context, mut contract, _) = setup();
+        let order_id = list_order(&mut context, &mut contract, Coordinate { lat: 0, lon: 0 });
+        register_courier(&mut context, &mut contract);
+        set_caller(&mut context, accounts(5), 0);
+        contract.place_bid(order_id.clone(), U128(SHIPPING_FEE), 30).unwrap();
+        let approval = json!({ "order_id": order_id, "courier_id": accounts(5) });
+        set_caller(&mut context, token(), 0);
+        let unused = contract.ft_on_transfer(accounts(2), U128(2 * SHIPPING_FEE), format!("approve_proposal|{}", approval));
+        assert_eq!(unused, "0");
+        set_caller(&mut context, accounts(5), 0);
+        assert!(matches!(contract.withdraw_bid(order_id), Err(InternalError::InvalidTransition)));
+    }
+
+    #[test]
+    fn shipping_declines_buyer_cancellation_request() {
+        let (mut context, mut contract, order_id) = setup();
+        set_caller(&mut context, accounts(1), 0);
+        contract.stage_order(order_id.clone()).unwrap();
+        set_caller(&mut context, accounts(2), 0);
+        assert_eq!(contract.cancel_order(order_id.clone()).unwrap(), None);
+        let cancel_requested = |contract: &Contract| {
+            let (order, _, _) = contract.orders.get(&accounts(2)).unwrap().get(&order_id).unwrap();
+            Order::from(order).cancel_requested
+        };
+        assert!(cancel_requested(&contract));
+        set_caller(&mut context, accounts(1), 0);
+        contract.ship_order(order_id.clone()).unwrap();
+        assert!(!cancel_requested(&contract));
+    }
+
+    #[test]
+    fn pending_claim_blocks_expiry_payout() {
+        let (mut context, mut contract, _) = setup();
+        let order_id = place_order(&mut context, &mut contract, 10, None);
+        approve_courier(&mut context, &mut contract, &order_id);
+        set_caller(&mut context, accounts(1), 0);
+        contract.stage_order(order_id.clone()).unwrap();
+        contract.ship_order(order_id.clone()).unwrap();
+        set_caller(&mut context, accounts(2), 0);
+        contract.file_claim(order_id.clone(), ClaimReason::LOST).unwrap();
+
+        context.block_timestamp((2 * DAY_MS + 1) * 1_000_000);
+        set_caller(&mut context, accounts(3), 0);
+        assert!(matches!(contract.claim_expired(order_id.clone()), Err(InternalError::ClaimPending)));
+        set_caller(&mut context, accounts(2), 0);
+        assert!(matches!(contract.confirm_delivery(order_id.clone()), Err(InternalError::ClaimPending)));
+
+        set_caller(&mut context, accounts(0), 0);
+        // The pool only holds this order's premium
+        assert_eq!(contract.approve_claim(order_id).unwrap(), U128(ORDER_TOTAL / 10));
+        assert_eq!(contract.get_balance(accounts(2), token()), U128(ORDER_TOTAL / 10));
+        assert_eq!(contract.get_balance(accounts(5), token()), U128(2 * SHIPPING_FEE / 100 * 99));
+    }
+
+    #[test]
+    fn migrate_keeps_current_state() {
+        let (_, contract, _) = setup();
+        env::storage_write(b"STATE", &contract.try_to_vec().unwrap());
+        let migrated = Contract::migrate(None, None);
+        assert_eq!(migrated.owner_id, accounts(0));
+        assert_eq!(migrated.order_nonce, contract.order_nonce);
+    }
+
+    #[test]
+    fn migrate_converts_first_release_state() {
+        let mut context = VMContextBuilder::new();
+        set_caller(&mut context, accounts(0), 0);
+        // `token_precision` followed by the prefixes of its eleven maps
+        let mut state = vec![18];
+        for prefix in b"blocdeghkiq" {
+            state.extend([1, 0, 0, 0, *prefix]);
+        }
+        env::storage_write(b"STATE", &state);
+
+        let migrated = Contract::migrate(Some(accounts(0)), Some(config()));
+        assert_eq!(migrated.state_version, migration::STATE_VERSION);
+        assert_eq!(migrated.owner_id, accounts(0));
+        assert_eq!(migrated.get_config().accepted_tokens, vec![(token(), 6)]);
+    }
+
+    #[test]
+    fn deliver_with_committed_code_pays_out() {
+        let (mut context, mut contract, _) = setup();
+        let code = [7u8; 16];
+        let code_hash = near_sdk::bs58::encode(env::sha256(&code)).into_string();
+        let order_id = place_order(&mut context, &mut contract, 0, Some(code_hash));
+        approve_courier(&mut context, &mut contract, &order_id);
+        set_caller(&mut context, accounts(1), 0);
+        contract.stage_order(order_id.clone()).unwrap();
+        contract.ship_order(order_id.clone()).unwrap();
+
+        set_caller(&mut context, accounts(5), 0);
+        let short_code = near_sdk::bs58::encode([7u8; 8]).into_string();
+        assert!(matches!(contract.deliver(order_id.clone(), short_code), Err(InternalError::InvalidPayload(_))));
+        let wrong_code = near_sdk::bs58::encode([8u8; 16]).into_string();
+        assert!(matches!(contract.deliver(order_id.clone(), wrong_code), Err(InternalError::Unauthorized)));
+        contract.deliver(order_id.clone(), near_sdk::bs58::encode(code).into_string()).unwrap();
+        assert_eq!(contract.get_balance(accounts(5), token()), U128(2 * SHIPPING_FEE / 100 * 99));
+        assert!(contract.delivery_codes.get(&order_id).is_none());
+    }
+
+    #[test]
+    fn second_courier_approval_is_refunded() {
+        let (mut context, mut contract, order_id) = setup();
+        approve_courier(&mut context, &mut contract, &order_id);
+        set_caller(&mut context, accounts(2), 0);
+        contract.place_proposal(accounts(3), order_id.clone()).unwrap();
+        set_caller(&mut context, accounts(3), 0);
+        contract.suggest_shipping_fee(order_id.clone(), SHIPPING_FEE).unwrap();
+        let approval = json!({ "order_id": order_id, "courier_id": accounts(3) });
+        set_caller(&mut context, token(), 0);
+        let unused = contract.ft_on_transfer(accounts(2), U128(2 * SHIPPING_FEE), format!("approve_proposal|{}", approval));
+        assert_eq!(unused, (2 * SHIPPING_FEE).to_string());
+        let (order, _, _) = contract.orders.get(&accounts(2)).unwrap().get(&order_id).unwrap();
+        assert_eq!(Order::from(order).courier, Some(accounts(5)));
+    }
+
+    #[test]
+    fn courier_stays_in_transit_until_last_shipment_settles() {
+        let (mut context, mut contract, first_order_id) = setup();
+        let second_order_id = place_order(&mut context, &mut contract, 0, None);
+        set_caller(&mut context, accounts(5), ONE_NEAR);
+        contract.storage_deposit(None, None).unwrap();
+        contract.register_courier(CourierUser {
+            name: "Courier".to_string(),
+            phone: "0711111111".to_string(),
+            email: "courier@example.com".to_string(),
+            image: "".to_string(),
+            vehicle: "motorcycle".to_string(),
+            make_model: "Boxer".to_string(),
+            plate_id: "KMA 001A".to_string(),
+        }).unwrap();
+        let on_transit = |contract: &Contract| {
+            User::from(contract.couriers.get(&accounts(5)).unwrap()).courier_profile.unwrap().on_transit
+        };
+
+        for order_id in [&first_order_id, &second_order_id] {
+            approve_courier(&mut context, &mut contract, order_id);
+            set_caller(&mut context, accounts(1), 0);
+            contract.stage_order(order_id.clone()).unwrap();
+            contract.ship_order(order_id.clone()).unwrap();
+        }
+        assert!(on_transit(&contract));
+        set_caller(&mut context, accounts(2), 0);
+        contract.confirm_delivery(first_order_id).unwrap();
+        assert!(on_transit(&contract));
+        contract.confirm_delivery(second_order_id).unwrap();
+        assert!(!on_transit(&contract));
+    }
+
+    #[test]
+    fn upgrade_without_enough_gas_keeps_proposal() {
+        let (mut context, mut contract, _) = setup();
+        set_caller(&mut context, accounts(0), 0);
+        contract.propose_upgrade(vec![0, 97, 115, 109].into()).unwrap();
+        context.block_timestamp((DAY_MS + 1) * 1_000_000);
+        testing_env!(context.prepaid_gas(near_sdk::Gas(10_000_000_000_000)).build());
+        assert!(matches!(contract.upgrade(), Err(InternalError::InsufficientGas)));
+        assert!(contract.get_upgrade_proposal().is_some());
+    }
 }
@@ -0,0 +1,241 @@
+use near_sdk::borsh::BorshSerialize;
+
+use crate::{*, utils::{Coordinate, StarRate, InternalError}};
+
+// Bumped whenever the layout of `Contract` or of a stored entity changes once deployed; `migrate`
+// upgrades older layouts to it. Version 1 is the first release after the unversioned one and
+// ships every layout change made since as a whole, so none of them needs a migration of its own.
+pub(crate) const STATE_VERSION: u16 = 1;
+
+// Layouts of the first release, whose maps were keyed by `String::from_utf8(sha256(account_id))`
+// and which also embed those ids in their values. Everything else is Borsh-compatible, since an
+// `AccountId` is serialized exactly like a `String`.
+#[derive(BorshDeserialize, BorshSerialize)]
+struct LegacyOrder {
+  amount: u128,
+  seller: String,
+  status: OrderStatus,
+  insurance: u8,
+  courier: Option<String>,
+  timestamp: u64,
+  location: Coordinate,
+}
+
+#[derive(BorshDeserialize, BorshSerialize)]
+struct LegacyLockedAmount {
+  receiver_id: AccountId,
+  amount: u128,
+}
+
+#[derive(BorshDeserialize, BorshSerialize)]
+struct LegacyCompany {
+  name: String,
+  wallet: AccountId,
+  phone: String,
+  email: String,
+  location: Coordinate,
+  sales: u64,
+  star_rate: StarRate,
+}
+
+#[derive(BorshDeserialize, BorshSerialize)]
+struct LegacyProposal {
+  client: String,
+  courier_id: Option<AccountId>,
+  amount: u128,
+  fee: u128,
+  status: ProposalStatus
+}
+
+type LegacyOrderRecord = (LegacyOrder, Vector<OrderItem>, Vector<String>);
+
+// The first release had neither a version prefix nor an owner or config. Its maps used the
+// prefixes `new` still uses, so only its layout is needed to recognise it; its entries are
+// moved over by `migrate_account_keys`.
+#[allow(dead_code)]
+#[derive(BorshDeserialize)]
+struct ContractV0 {
+  token_precision: u8,
+  balances: LookupMap<String, Account>,
+  locked_balances: LookupMap<String, UnorderedMap<String, LegacyLockedAmount>>,
+  orders: LookupMap<String, UnorderedMap<String, LegacyOrderRecord>>,
+  orders_pending: LookupMap<String, UnorderedMap<String, String>>,
+  orders_staged: LookupMap<String, UnorderedMap<String, String>>,
+  orders_shipping: LookupMap<String, UnorderedMap<String, String>>,
+  couriers: LookupMap<String, User>,
+  couriers_by_company: LookupMap<String, UnorderedMap<String, u64>>,
+  courier_companies: LookupMap<String, Vector<String>>,
+  companies: LookupMap<String, LegacyCompany>,
+  proposals: LookupMap<String, UnorderedMap<String, LegacyProposal>>
+}
+
+#[near_bindgen]
+impl Contract {
+  // Runs right after new code is deployed. `state_version` is the first field of every layout
+  // since the first release, so it can be read before deciding how to decode the rest of the
+  // state. Converting the first release needs the `owner_id` and `config` `new` would take.
+  #[private]
+  #[init(ignore_state)]
+  pub fn migrate(owner_id: Option<AccountId>, config: Option<Config>) -> Self {
+    let state = env::storage_read(b"STATE").unwrap_or_else(|| env::panic_str("Contract is not initialized!"));
+    let state_version = u16::deserialize(&mut state.as_slice()).unwrap_or_else(|_| env::panic_str("Cannot read state version!"));
+    match state_version {
+      STATE_VERSION => deserialize(&state),
+      _ if ContractV0::try_from_slice(&state).is_ok() => {
+        let (owner_id, config) = owner_id.zip(config).unwrap_or_else(|| {
+          env::panic_str("Migrating the first release needs an owner and a config!")
+        });
+        Self::init(owner_id, config)
+      },
+      _ => env::panic_str("Unknown state version!")
+    }
+  }
+
+  // Moves state stored under the old hashed keys to plain account id keys. Ids embedded in
+  // orders, proposals and indexes are resolved against the same list, so a batch has to include
+  // every buyer, seller and courier its entries reference; sellers and companies the first
+  // release stored by their plain account id are taken as is. That release only took a single
+  // token, so its balances, escrows and orders are all assigned to `token_id`.
+  #[handle_result]
+  pub fn migrate_account_keys(&mut self, account_ids: Vec<AccountId>, token_id: AccountId) -> Result<u32, InternalError> {
+    self.assert_owner()?;
+    if self.accepted_tokens.get(&token_id).is_none() {
+      return Err(InternalError::UnknownToken)
+    }
+    let legacy_ids: Vec<(String, AccountId)> = account_ids.into_iter().filter_map(|account_id| {
+      String::from_utf8(env::sha256(account_id.as_bytes())).ok().map(|legacy_id| (legacy_id, account_id))
+    }).collect();
+    let resolve = |legacy_id: &String| -> AccountId {
+      legacy_ids.iter().find(|(id, _)| id == legacy_id).map(|(_, account_id)| account_id.clone())
+        .or_else(|| legacy_id.parse().ok())
+        .unwrap_or_else(|| env::panic_str("Unresolved legacy account id!"))
+    };
+    // Escrows restart their clock, since the first release had no deadlines
+    let deadline = env::block_timestamp_ms() + self.config.shipping_timeout_ms;
+    let mut legacy_companies: LookupMap<String, LegacyCompany> = LookupMap::new(b"i");
+    let mut approved_fees: Vec<(AccountId, String, u128)> = vec![];
+
+    for (legacy_id, account_id) in legacy_ids.iter() {
+      let old_key = raw(legacy_id);
+      let new_key = raw(account_id);
+
+      if let Some(account) = self.balances.remove_raw(&old_key) {
+        self.balances.insert_raw(&raw(&(account_id, &token_id)), &account);
+      }
+      if let Some(locked_balances) = self.locked_balances.remove_raw(&old_key) {
+        let mut locked_balances: UnorderedMap<String, LegacyLockedAmount> = deserialize(&locked_balances);
+        for (escrow_id, locked_amount) in locked_balances.to_vec() {
+          let updated_amount = LockedAmount {
+            receiver_id: locked_amount.receiver_id,
+            token_id: token_id.clone(),
+            amount: locked_amount.amount,
+            deadline,
+            courier_amount: 0
+          };
+          locked_balances.insert_raw(&raw(&escrow_id), &raw(&updated_amount));
+        }
+        self.locked_balances.insert_raw(&new_key, &raw(&locked_balances));
+      }
+      if let Some(user) = self.couriers.remove_raw(&old_key) {
+        self.couriers.insert(account_id, &deserialize::<User>(&user).into());
+      }
+      if let Some(company) = legacy_companies.remove(legacy_id).or_else(|| legacy_companies.remove(&account_id.to_string())) {
+        self.companies.insert(account_id, &Company {
+          owner: account_id.clone(),
+          name: company.name,
+          wallet: company.wallet,
+          phone: company.phone,
+          email: company.email,
+          location: company.location,
+          sales: company.sales,
+          star_rate: company.star_rate
+        });
+      }
+
+      if let Some(my_orders) = self.orders.remove_raw(&old_key) {
+        let mut my_orders: UnorderedMap<String, (LegacyOrder, Vector<OrderItem>, Vector<String>)> = deserialize(&my_orders);
+        for (order_id, (order, items, mut couriers)) in my_orders.to_vec() {
+          for index in 0..couriers.len() {
+            couriers.replace_raw(index, &raw(&resolve(&couriers.get(index).unwrap())));
+          }
+          let updated_order = Order {
+            amount: order.amount,
+            token_id: token_id.clone(),
+            seller: resolve(&order.seller),
+            status: order.status,
+            insurance: order.insurance,
+            courier: order.courier.as_ref().map(resolve),
+            timestamp: order.timestamp,
+            location: order.location,
+            cancel_requested: false,
+          };
+          my_orders.insert_raw(&raw(&order_id), &raw(&(VersionedOrder::from(updated_order), items, couriers)));
+        }
+        self.orders.insert_raw(&new_key, &raw(&my_orders));
+      }
+
+      for index in [&mut self.orders_pending, &mut self.orders_staged, &mut self.orders_shipping] {
+        if let Some(seller_orders) = index.remove_raw(&old_key) {
+          let mut seller_orders: UnorderedMap<String, String> = deserialize(&seller_orders);
+          for (order_id, buyer_id) in seller_orders.to_vec() {
+            seller_orders.insert_raw(&raw(&order_id), &raw(&resolve(&buyer_id)));
+          }
+          index.insert_raw(&new_key, &raw(&seller_orders));
+        }
+      }
+
+      if let Some(company_couriers) = self.couriers_by_company.remove_raw(&old_key) {
+        let mut company_couriers: UnorderedMap<String, u64> = deserialize(&company_couriers);
+        for (courier_id, deliveries) in company_couriers.to_vec() {
+          company_couriers.remove(&courier_id);
+          company_couriers.insert_raw(&raw(&resolve(&courier_id)), &raw(&deliveries));
+        }
+        self.couriers_by_company.insert_raw(&new_key, &raw(&company_couriers));
+      }
+      if let Some(courier_companies) = self.courier_companies.remove_raw(&old_key) {
+        let mut courier_companies: Vector<String> = deserialize(&courier_companies);
+        for index in 0..courier_companies.len() {
+          courier_companies.replace_raw(index, &raw(&resolve(&courier_companies.get(index).unwrap())));
+        }
+        self.courier_companies.insert_raw(&new_key, &raw(&courier_companies));
+      }
+
+      if let Some(courier_proposals) = self.proposals.remove_raw(&old_key) {
+        let mut courier_proposals: UnorderedMap<String, LegacyProposal> = deserialize(&courier_proposals);
+        for (order_id, proposal) in courier_proposals.to_vec() {
+          if proposal.status == ProposalStatus::APPROVED {
+            approved_fees.push((resolve(&proposal.client), order_id.clone(), proposal.amount));
+          }
+          let updated_proposal = Proposal {
+            client: resolve(&proposal.client),
+            courier_id: Some(account_id.clone()),
+            amount: proposal.amount,
+            fee: proposal.fee,
+            status: proposal.status,
+            eta_minutes: None
+          };
+          courier_proposals.insert_raw(&raw(&order_id), &raw(&VersionedProposal::from(updated_proposal)));
+        }
+        self.proposals.insert_raw(&new_key, &raw(&courier_proposals));
+      }
+    }
+    // Approved courier fees were collected but never escrowed, so they join their order's
+    // escrow once every buyer in the batch has been moved, or go back to refunded buyers
+    for (buyer_id, order_id, amount) in approved_fees {
+      if self.get_locked_balance(buyer_id.clone(), order_id.clone()).is_some() {
+        self.lock_courier_fee(&buyer_id, &order_id, amount)?;
+      } else {
+        self.credit_balance(&buyer_id, &token_id, amount);
+      }
+    }
+    Ok(legacy_ids.len() as u32)
+  }
+}
+
+fn raw<T: BorshSerialize>(value: &T) -> Vec<u8> {
+  value.try_to_vec().unwrap_or_else(|_| env::panic_str("Cannot serialize value!"))
+}
+
+fn deserialize<T: BorshDeserialize>(raw: &[u8]) -> T {
+  T::try_from_slice(raw).unwrap_or_else(|_| env::panic_str("Cannot deserialize legacy value!"))
+}
@@ -0,0 +1,141 @@
+use near_sdk::{assert_one_yocto, Promise, StorageUsage};
+
+use crate::{*, utils::{StorageAccount, StorageBalance, StorageBalanceBounds, InternalError}};
+
+// Bytes an entry in `storage_accounts` takes: 1 prefix + 68 account id (max length) + 24 value
+// + 40 for the trie record itself. Every registered account pays for at least this much.
+const ACCOUNT_STORAGE_BYTES: StorageUsage = 133;
+
+// NEP-145: buyers, sellers and couriers prepay the storage their records occupy.
+#[near_bindgen]
+impl Contract {
+  #[payable]
+  #[handle_result]
+  pub fn storage_deposit(&mut self, account_id: Option<AccountId>, registration_only: Option<bool>) -> Result<StorageBalance, InternalError> {
+    let amount = env::attached_deposit();
+    let account_id = account_id.unwrap_or_else(env::predecessor_account_id);
+    let registration_only = registration_only.unwrap_or(false);
+    let min_balance = self.storage_balance_bounds().min.0;
+
+    if let Some(mut storage_account) = self.storage_accounts.get(&account_id) {
+      if registration_only {
+        if amount > 0 {
+          Promise::new(env::predecessor_account_id()).transfer(amount);
+        }
+      } else {
+        storage_account.deposit += amount;
+        self.storage_accounts.insert(&account_id, &storage_account);
+      }
+    } else {
+      if amount < min_balance {
+        return Err(InternalError::InsufficientStorage)
+      }
+      let deposit = if registration_only {
+        let refund = amount - min_balance;
+        if refund > 0 {
+          Promise::new(env::predecessor_account_id()).transfer(refund);
+        }
+        min_balance
+      } else {
+        amount
+      };
+      let storage_account = StorageAccount {
+        deposit,
+        used_bytes: ACCOUNT_STORAGE_BYTES
+      };
+      self.storage_accounts.insert(&account_id, &storage_account);
+    }
+    self.storage_balance_of(account_id).ok_or(InternalError::NotRegistered)
+  }
+
+  #[payable]
+  #[handle_result]
+  pub fn storage_withdraw(&mut self, amount: Option<U128>) -> Result<StorageBalance, InternalError> {
+    assert_one_yocto();
+    let account_id = env::predecessor_account_id();
+    let mut storage_account = self.storage_accounts.get(&account_id).ok_or(InternalError::NotRegistered)?;
+    let available = storage_account.deposit.saturating_sub(self.storage_cost(&storage_account));
+    let amount = amount.map(|amount| amount.0).unwrap_or(available);
+    if amount > available {
+      return Err(InternalError::InsufficientStorage)
+    }
+
+    storage_account.deposit -= amount;
+    self.storage_accounts.insert(&account_id, &storage_account);
+    if amount > 0 {
+      Promise::new(account_id.clone()).transfer(amount);
+    }
+    self.storage_balance_of(account_id).ok_or(InternalError::NotRegistered)
+  }
+
+  // Only accounts without records can leave; `force` is not supported since orders and
+  // escrowed funds cannot be burned on behalf of other parties.
+  #[payable]
+  #[handle_result]
+  pub fn storage_unregister(&mut self, force: Option<bool>) -> Result<bool, InternalError> {
+    assert_one_yocto();
+    let account_id = env::predecessor_account_id();
+    let storage_account = match self.storage_accounts.get(&account_id) {
+      Some(storage_account) => storage_account,
+      None => return Ok(false)
+    };
+    if storage_account.used_bytes > ACCOUNT_STORAGE_BYTES || force.unwrap_or(false) {
+      return Err(InternalError::StorageInUse)
+    }
+
+    self.storage_accounts.remove(&account_id);
+    Promise::new(account_id).transfer(storage_account.deposit);
+    Ok(true)
+  }
+
+  pub fn storage_balance_bounds(&self) -> StorageBalanceBounds {
+    StorageBalanceBounds {
+      min: U128(ACCOUNT_STORAGE_BYTES as u128 * env::storage_byte_cost()),
+      max: None
+    }
+  }
+
+  pub fn storage_balance_of(&self, account_id: AccountId) -> Option<StorageBalance> {
+    self.storage_accounts.get(&account_id).map(|storage_account| {
+      StorageBalance {
+        total: U128(storage_account.deposit),
+        available: U128(storage_account.deposit.saturating_sub(self.storage_cost(&storage_account)))
+      }
+    })
+  }
+
+  // Bills `account_id` for the bytes written (or credits the bytes freed) since `initial_storage`
+  // was read, failing when its deposit no longer covers what it uses.
+  pub(crate) fn charge_storage(&mut self, account_id: &AccountId, initial_storage: StorageUsage) -> Result<(), InternalError> {
+    let mut storage_account = self.storage_accounts.get(account_id).ok_or(InternalError::NotRegistered)?;
+    storage_account.used_bytes = used_bytes_since(storage_account.used_bytes, initial_storage);
+    if self.storage_cost(&storage_account) > storage_account.deposit {
+      return Err(InternalError::InsufficientStorage)
+    }
+    self.storage_accounts.insert(account_id, &storage_account);
+    Ok(())
+  }
+
+  // Like `charge_storage`, but for settlements, which mostly free records and must never be
+  // blocked by storage: bytes written for payees' new balances are billed without the deposit
+  // check, and an uncovered balance only keeps `account_id` from withdrawing or storing more.
+  pub(crate) fn settle_storage(&mut self, account_id: &AccountId, initial_storage: StorageUsage) {
+    if let Some(mut storage_account) = self.storage_accounts.get(account_id) {
+      storage_account.used_bytes = used_bytes_since(storage_account.used_bytes, initial_storage);
+      self.storage_accounts.insert(account_id, &storage_account);
+    }
+  }
+
+  fn storage_cost(&self, storage_account: &StorageAccount) -> u128 {
+    storage_account.used_bytes as u128 * env::storage_byte_cost()
+  }
+}
+
+fn used_bytes_since(used_bytes: StorageUsage, initial_storage: StorageUsage) -> StorageUsage {
+  let current_storage = env::storage_usage();
+  if current_storage >= initial_storage {
+    used_bytes + (current_storage - initial_storage)
+  } else {
+    used_bytes.saturating_sub(initial_storage - current_storage).max(ACCOUNT_STORAGE_BYTES)
+  }
+}
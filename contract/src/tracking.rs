@@ -0,0 +1,77 @@
+use crate::{*, utils::{Checkpoint, Coordinate, check_coordinate, InternalError, DTradeEvent, PostCheckpointData}};
+
+const MAX_CHECKPOINTS: u64 = 100;
+const MAX_NOTE_LENGTH: usize = 256;
+
+#[near_bindgen]
+impl Contract {
+  // The courier assigned to a shipping order records where the parcel is. Checkpoints are only
+  // ever appended, so the buyer sees the delivery exactly as the courier reported it.
+  #[handle_result]
+  pub fn post_checkpoint(&mut self, order_id: String, location: Coordinate, note: String) -> Result<(), InternalError> {
+    let courier_id = env::predecessor_account_id();
+    let initial_storage = env::storage_usage();
+    let buyer_id = self.order_buyers.get(&order_id).ok_or(InternalError::NotFound)?;
+    let (order, _, _) = self.orders.get(&buyer_id).and_then(|my_orders| my_orders.get(&order_id)).ok_or(InternalError::NotFound)?;
+    let order = Order::from(order);
+    if order.courier.as_ref() != Some(&courier_id) {
+      return Err(InternalError::Unauthorized)
+    }
+    if !matches!(order.status, OrderStatus::SHIPPING) {
+      return Err(InternalError::InvalidTransition)
+    }
+    check_coordinate(&location)?;
+    if note.len() > MAX_NOTE_LENGTH {
+      return Err(InternalError::InvalidPayload(format!("note cannot exceed {} bytes", MAX_NOTE_LENGTH)))
+    }
+
+    let mut checkpoints = self.tracking.get(&order_id).unwrap_or_else(|| {
+      let prefix: Vec<u8> = [
+          b"K".as_slice(),
+          &near_sdk::env::sha256_array(order_id.as_bytes()),
+        ]
+        .concat();
+      Vector::new(prefix)
+    });
+    if checkpoints.len() >= MAX_CHECKPOINTS {
+      return Err(InternalError::InvalidPayload(format!("an order cannot have more than {} checkpoints", MAX_CHECKPOINTS)))
+    }
+    let checkpoint = Checkpoint {
+      timestamp: env::block_timestamp_ms(),
+      location,
+      note
+    };
+    checkpoints.push(&checkpoint);
+    self.tracking.insert(&order_id, &checkpoints);
+    DTradeEvent::PostCheckpoint(vec![PostCheckpointData {
+      order_id: &order_id,
+      courier_id: &courier_id,
+      location: &checkpoint.location,
+      note: &checkpoint.note,
+    }]).emit();
+    self.charge_storage(&courier_id, initial_storage)
+  }
+
+  // Checkpoints of an order, oldest first.
+  pub fn get_tracking(&self, order_id: String) -> Vec<Checkpoint> {
+    self.tracking.get(&order_id).map(|checkpoints| checkpoints.to_vec()).unwrap_or_default()
+  }
+
+  // Counts the orders a courier is shipping, which keeps `on_transit` set until the last is settled.
+  pub(crate) fn track_shipment(&mut self, courier_id: &AccountId, started: bool) {
+    let shipments = self.active_shipments.get(courier_id).unwrap_or(0);
+    let shipments = if started { shipments + 1 } else { shipments.saturating_sub(1) };
+    if shipments == 0 {
+      self.active_shipments.remove(courier_id);
+    } else {
+      self.active_shipments.insert(courier_id, &shipments);
+    }
+    if let Some(user) = self.couriers.get(courier_id) {
+      let mut user = User::from(user);
+      if let Some(courier) = user.courier_profile.as_mut() {
+        courier.on_transit = shipments > 0;
+        self.couriers.insert(courier_id, &user.into());
+      }
+    }
+  }
+}
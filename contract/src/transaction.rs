@@ -3,83 +3,143 @@ use std::ops::{Mul, Sub};
 
 use near_sdk::{serde_json};
 
-use crate::{*, utils::{OrderItem, Cart, CartItem, ProposalApproval, Coordinate}};
+use crate::{*, utils::{OrderItem, Cart, CartItem, ProposalApproval, Coordinate, InternalError, check_coordinate, DTradeEvent, PlaceOrderData, ApproveProposalData}};
+
+// A validated cart, as `register_order` stores it.
+struct NewOrder {
+  token_id: AccountId,
+  date: u64,
+  total_cost: u128,
+  items: Vec<OrderItem>,
+  seller: AccountId,
+  seller_wallet: AccountId,
+  list_for_bidding: bool,
+  percentage_insurance: u8,
+  location: Coordinate,
+}
 
 #[near_bindgen]
 impl Contract {
-  pub fn approve_proposal(&self, account_id: AccountId, amount: u128, payload: String) -> Option<u128> {
-    let proposal_approval: ProposalApproval = match serde_json::from_str(payload.as_str()) {
-      Ok(_proposal) => _proposal,
-      Err(_error) => {
-        return None
-      }
+  pub(crate) fn approve_proposal(&mut self, account_id: AccountId, token_id: AccountId, amount: u128, payload: String) -> Result<u128, InternalError> {
+    let proposal_approval: ProposalApproval = serde_json::from_str(payload.as_str())
+      .map_err(|error| InternalError::InvalidPayload(error.to_string()))?;
+    let buyer_id = account_id;
+    let mut proposals = self.proposals.get(&proposal_approval.courier_id).ok_or(InternalError::NotFound)?;
+    let proposal = proposals.get(&proposal_approval.order_id).map(Proposal::from).ok_or(InternalError::NotFound)?;
+    // Only a fee the courier has proposed can be accepted
+    if proposal.status != ProposalStatus::PROPOSED {
+      return Err(InternalError::InvalidTransition)
+    }
+    let expected_amount = proposal.fee.mul(2);
+    if amount.lt(&expected_amount) {
+      return Err(InternalError::InsufficientFunds)
+    }
+    let balance = amount.sub(expected_amount);
+    let new_proposal = Proposal {
+      amount: expected_amount,
+      status: ProposalStatus::APPROVED,
+      ..proposal
     };
-    let buyer_id = self.get_hash(account_id);
-    if let Some(proposals) = self.proposals.get(&proposal_approval.courier_id) {
-      if let Some(proposal) = proposals.get(&proposal_approval.order_id) {
-        let expected_amount = proposal.fee.mul(2);
-        if amount.ge(&expected_amount) {
-          let balance = amount.sub(expected_amount);
-          let new_proposal = Proposal {
-            amount: expected_amount,
-            status: ProposalStatus::APPROVED,
-            ..proposal
-          };
-          // update order
-          if let Some(_order) = self.orders.get(&buyer_id) {
-            if let Some(order) = _order.get(&proposal_approval.order_id) {
-              let new_order = Order {
-                courier: Some(proposal_approval.courier_id.clone()),
-                ..order.0
-              };
-              self.orders.get(&buyer_id).unwrap().insert(&proposal_approval.order_id, &(new_order, order.1, order.2));
-              // update proposal
-              self.proposals.get(&proposal_approval.courier_id).unwrap().insert(&proposal_approval.order_id, &new_proposal);
-              return Some(balance)
-            }
-          }
-        }
-      }
+    // update order
+    let mut my_orders = self.orders.get(&buyer_id).ok_or(InternalError::NotFound)?;
+    let (order, items, couriers) = my_orders.get(&proposal_approval.order_id).ok_or(InternalError::NotFound)?;
+    let order = Order::from(order);
+    // Couriers are paid out in the same token the order was paid with
+    if order.token_id != token_id {
+      return Err(InternalError::InvalidPayload("proposal must be paid with the order token".to_string()))
+    }
+    // A courier is picked once, before the order ships
+    if !matches!(order.status, OrderStatus::PENDING | OrderStatus::STAGGED) {
+      return Err(InternalError::InvalidTransition)
+    }
+    let approved = order.courier.as_ref()
+      .and_then(|courier_id| self.proposals.get(courier_id))
+      .and_then(|courier_proposals| courier_proposals.get(&proposal_approval.order_id))
+      .is_some_and(|proposal| Proposal::from(proposal).status == ProposalStatus::APPROVED);
+    if approved {
+      return Err(InternalError::AlreadyExists)
     }
-    None
+    self.lock_courier_fee(&buyer_id, &proposal_approval.order_id, expected_amount)?;
+    let new_order = Order {
+      courier: Some(proposal_approval.courier_id.clone()),
+      ..order
+    };
+    my_orders.insert(&proposal_approval.order_id, &(new_order.into(), items, couriers));
+    // update proposal
+    proposals.insert(&proposal_approval.order_id, &new_proposal.into());
+    self.delist_open_order(&proposal_approval.order_id);
+    DTradeEvent::ApproveProposal(vec![ApproveProposalData {
+      order_id: &proposal_approval.order_id,
+      buyer_id: &buyer_id,
+      courier_id: &proposal_approval.courier_id,
+      amount: U128(expected_amount),
+    }]).emit();
+    Ok(balance)
   }
 
-    // #[handle_result]
- pub fn place_order(&mut self, account_id: AccountId, amount_paid: u128, products_ordered: String, decimal: u8) -> Option<u128> /*Result<u128, InternalError>*/ {
+  pub(crate) fn place_order(&mut self, account_id: AccountId, token_id: AccountId, amount_paid: u128, products_ordered: String) -> Result<u128, InternalError> {
     // Deceserialize cart object
-    let cart: Cart = match serde_json::from_str(products_ordered.as_str()) {
-      Ok(_cart) => _cart,
-      Err(_error) => {
-        return None // Err(InternalError::Unexpected(error.to_string()))
-      }
+    let cart: Cart = serde_json::from_str(products_ordered.as_str())
+      .map_err(|error| InternalError::InvalidPayload(error.to_string()))?;
+    // Validate everything before touching storage: failures are refunded, not reverted
+    let company = self.companies.get(&cart.seller).ok_or(InternalError::UnknownCompany)?;
+    check_coordinate(&cart.location)?;
+    let (amount, items) = self.process_ordered_items(cart.items)?;
+    if amount == 0 {
+      return Err(InternalError::InvalidPayload("cart is empty".to_string()))
+    }
+    if cart.percentage_insurance > 100 {
+      return Err(InternalError::InvalidPayload("insurance cannot exceed 100 percent".to_string()))
+    }
+    // The premium is charged on top of the item total and pooled, it is not held in escrow
+    let premium = treasury::bps_share(amount, cart.percentage_insurance as u16 * 100);
+    let total = amount.checked_add(premium).ok_or(InternalError::InsufficientFunds)?;
+    if amount_paid < total {
+      return Err(InternalError::InsufficientFunds)
+    }
+    let delivery_code_hash = match cart.delivery_code_hash {
+      Some(hash) => Some(near_sdk::bs58::decode(&hash).into_vec().ok().filter(|hash| hash.len() == 32)
+        .ok_or_else(|| InternalError::InvalidPayload("delivery code hash must be a base58 sha256".to_string()))?),
+      None => None
     };
-    
+
     let date = env::block_timestamp_ms();
     // Get order id
-    let order_id = match String::from_utf8(near_sdk::env::sha256(date.to_string().as_bytes()).to_vec()) {
-      Ok(_order_id) => _order_id,
-      Err(_error) => {
-        return None // Err(InternalError::Unexpected(error.to_string()))
-      }
-    };
+    let order_id = self.next_order_id(&account_id);
 
-    if let Some((amount, items_store, couriers)) = self.process_ordered_items(&order_id, cart.items, decimal) {
-      if let Some(balance) = self.register_order(&account_id, date, amount, items_store, couriers, amount_paid, &order_id, cart.seller, cart.list_for_bidding, cart.percentage_insurance, cart.location) {
-        return Some(balance) // Ok(balance)
-      }
-      return None // Err(InternalError::Unexpected("Error placing order!".to_string()))
+    self.register_order(&account_id, &order_id, NewOrder {
+      token_id: token_id.clone(),
+      date,
+      total_cost: amount,
+      items,
+      seller: cart.seller.clone(),
+      seller_wallet: company.wallet,
+      list_for_bidding: cart.list_for_bidding,
+      percentage_insurance: cart.percentage_insurance,
+      location: cart.location,
+    })?;
+    if cart.list_for_bidding {
+      self.list_open_order(&order_id, &account_id, &company.location, date + self.config.bidding_window_ms);
     }
-    return None // Err(InternalError::Unexpected("Error processing ordered items!".to_string()))
- }
+    if let Some(hash) = delivery_code_hash {
+      self.delivery_codes.insert(&order_id, &hash);
+    }
+    self.pool_premium(&token_id, premium);
+    DTradeEvent::PlaceOrder(vec![PlaceOrderData {
+      order_id: &order_id,
+      buyer_id: &account_id,
+      seller_id: &cart.seller,
+      token_id: &token_id,
+      amount: U128(amount),
+      insurance_premium: U128(premium),
+    }]).emit();
+    Ok(amount_paid - total)
+  }
 
-  fn register_order(&mut self, account_id: &AccountId, date: u64, total_cost: u128, items_store: Vector<OrderItem>, couriers: Vector<String>, amount_paid: u128, order_id: &String, seller: String, list_for_bidding: bool, percentage_insurance: u8, location: Coordinate) -> Option<u128> {
+  fn register_order(&mut self, account_id: &AccountId, order_id: &String, new_order: NewOrder) -> Result<(), InternalError> {
     // Get user id
-    let id: String = match String::from_utf8(near_sdk::env::sha256(account_id.as_bytes()).to_vec()) {
-      Ok(_id) => _id,
-      Err(_error) => {
-        return None // Err(InternalError::Unexpected(error.to_string()))
-      }
-    };
+    let id = account_id.clone();
+    let NewOrder { token_id, date, total_cost, items, seller, seller_wallet, list_for_bidding, percentage_insurance, location } = new_order;
 
     let mut my_orders = self.orders.get(&id).unwrap_or_else(|| {
         let prefix: Vec<u8> = [
@@ -89,71 +149,85 @@ impl Contract {
         .concat();
         UnorderedMap::new(prefix)
     });
+    if my_orders.get(order_id).is_some() {
+      return Err(InternalError::OrderExists)
+    }
+
+    let prefix: Vec<u8> = [
+            b"p".as_slice(),
+            &near_sdk::env::sha256_array(order_id.as_bytes()),
+        ]
+        .concat();
+    let mut items_store = Vector::new(prefix);
+    items_store.extend(items);
 
-    let courier = if list_for_bidding == true { None } else { Some(id.clone()) };
+    let courier_prefix: Vec<u8> = [
+            b"s".as_slice(),
+            &near_sdk::env::sha256_array(order_id.as_bytes()),
+        ]
+        .concat();
+    let couriers = Vector::new(courier_prefix);
+
+    let courier = if list_for_bidding { None } else { Some(id.clone()) };
     let order = Order {
         amount: total_cost,
+        token_id: token_id.clone(),
         seller: seller.clone(),
         status: utils::OrderStatus::PENDING,
         insurance: percentage_insurance,
         timestamp: date,
         courier,
         location,
+        cancel_requested: false,
     };
 
-    let value = (order, items_store, couriers);
-    if amount_paid < total_cost {
-      return None // Err(InternalError::Unexpected("Insufficient funds!".to_string()))
-    }
-    if my_orders.insert(order_id, &value).is_none() {
-      if let Some(_res) = self.update_pending_orders(&seller, order_id, &id) {
-        if let Some(company) = self.companies.get(&seller) {
-          // Check if enough money has been paid
-          self.lock_balance(account_id, order_id, company.wallet, total_cost);
-          let bal = amount_paid - total_cost;
-          return Some(bal) // Ok(bal)
-        }
-      }
-    }
-    return None // Err(InternalError::Unexpected("Order exists!".to_string()))
+    let value = (order.into(), items_store, couriers);
+    my_orders.insert(order_id, &value);
+    self.orders.insert(&id, &my_orders);
+    self.order_buyers.insert(order_id, &id);
+    self.update_pending_orders(&seller, order_id, &id);
+    self.lock_balance(account_id, order_id, seller_wallet, token_id, total_cost)
   }
 
-  fn process_ordered_items(&self, order_id: &String, cart_items: Vec<CartItem>, decimal: u8) -> Option<(u128, Vector<OrderItem>, Vector<String>)> {
+  fn process_ordered_items(&self, cart_items: Vec<CartItem>) -> Result<(u128, Vec<OrderItem>), InternalError> {
     let mut amount: u128 = 0;
-    let prefix: Vec<u8> = [
-            b"p".as_slice(),
-            &near_sdk::env::sha256_array(order_id.as_bytes()),
-        ]
-        .concat();
-
-    let mut items_store = Vector::new(prefix);
+    let mut items: Vec<OrderItem> = vec![];
 
-    cart_items.into_iter().for_each(|item| {
-    // convert price to u128
-    let price = (item.price * 10_f64.powi(decimal.into())) as u128;
-    // create OrderItem object
-    let order_item = OrderItem {
-      price,
-      name: item.name,
-      serial: item.serial,
-      quantity: item.quantity,
-      reference: item.reference
-    };
-    items_store.push(&order_item);
-    // Add price to total amount
-    amount = amount.checked_add(price).unwrap_or(amount);
-    });
-    let courier_prefix: Vec<u8> = [
-            b"s".as_slice(),
-            &near_sdk::env::sha256_array(order_id.as_bytes()),
-        ]
-        .concat();
+    for item in cart_items {
+      if item.quantity == 0 {
+        return Err(InternalError::InvalidPayload(format!("item {} has no quantity", item.serial)))
+      }
+      // Add price times quantity to total amount
+      amount = item.price.0.checked_mul(item.quantity.into())
+        .and_then(|subtotal| amount.checked_add(subtotal))
+        .ok_or_else(|| InternalError::InvalidPayload("order total overflows".to_string()))?;
+      // create OrderItem object
+      let order_item = OrderItem {
+        price: item.price.0,
+        name: item.name,
+        serial: item.serial,
+        quantity: item.quantity,
+        reference: item.reference
+      };
+      items.push(order_item);
+    }
+    Ok((amount, items))
+  }
 
-    let couriers = Vector::new(courier_prefix);
-    Some((amount, items_store, couriers))
+  // Order ids are the base58 sha256 of a contract-wide counter, the buyer and the block height,
+  // so two orders in the same block can never collide.
+  fn next_order_id(&mut self, account_id: &AccountId) -> String {
+    self.order_nonce += 1;
+    let seed: Vec<u8> = [
+        self.order_nonce.to_le_bytes().as_slice(),
+        account_id.as_bytes(),
+        &env::block_height().to_le_bytes(),
+      ]
+      .concat();
+    near_sdk::bs58::encode(env::sha256_array(&seed)).into_string()
   }
 
-  fn update_pending_orders(&self, seller_id: &String, order_id: &String, user_id: &String) -> Option<String> {
+  fn update_pending_orders(&mut self, seller_id: &AccountId, order_id: &String, user_id: &AccountId) {
     let mut seller_pending_orders = self.orders_pending.get(seller_id).unwrap_or_else(|| {
         let prefix: Vec<u8> = [
             b"f".as_slice(),
@@ -163,6 +237,6 @@ impl Contract {
         UnorderedMap::new(prefix)
     });
     seller_pending_orders.insert(order_id, user_id);
-    Some("".to_string())
+    self.orders_pending.insert(seller_id, &seller_pending_orders);
   }
 }
\ No newline at end of file
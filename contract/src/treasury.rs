@@ -0,0 +1,66 @@
+use near_sdk::{require, assert_one_yocto, Promise, PromiseResult};
+
+use crate::{*, escrow::{ext_ft, GAS_FOR_FT_TRANSFER, GAS_FOR_RESOLVE_WITHDRAW}, utils::InternalError};
+const MAX_BPS: u128 = 10_000;
+
+#[near_bindgen]
+impl Contract {
+  pub fn get_treasury(&self) -> Vec<(AccountId, U128)> {
+    self.treasury.iter().map(|(token_id, amount)| (token_id, U128(amount))).collect()
+  }
+
+  // Sends collected platform fees to the owner, restoring them if the transfer fails.
+  #[payable]
+  #[handle_result]
+  pub fn withdraw_treasury(&mut self, token_id: AccountId, amount: U128) -> Result<Promise, InternalError> {
+    assert_one_yocto();
+    self.assert_owner()?;
+    let collected = self.treasury.get(&token_id).unwrap_or(0);
+    if amount.0 == 0 || collected < amount.0 {
+      return Err(InternalError::InsufficientFunds)
+    }
+    self.treasury.insert(&token_id, &(collected - amount.0));
+
+    Ok(ext_ft::ext(token_id.clone())
+      .with_attached_deposit(1)
+      .with_static_gas(GAS_FOR_FT_TRANSFER)
+      .ft_transfer(self.owner_id.clone(), amount, Some("D-Trade treasury withdrawal".to_string()))
+      .then(
+        Self::ext(env::current_account_id())
+          .with_static_gas(GAS_FOR_RESOLVE_WITHDRAW)
+          .resolve_treasury_withdraw(token_id, amount)
+      ))
+  }
+
+  #[private]
+  pub fn resolve_treasury_withdraw(&mut self, token_id: AccountId, amount: U128) -> U128 {
+    require!(env::promise_results_count() == 1, "Unexpected promise results!");
+    match env::promise_result(0) {
+      PromiseResult::Successful(_) => amount,
+      _ => {
+        self.credit_treasury(&token_id, amount.0);
+        U128(0)
+      }
+    }
+  }
+
+  // Moves the platform fee on `amount` into the treasury and returns what is left for the payee.
+  pub(crate) fn collect_fee(&mut self, token_id: &AccountId, amount: u128) -> u128 {
+    let fee = bps_share(amount, self.config.platform_fee_bps);
+    if fee > 0 {
+      self.credit_treasury(token_id, fee);
+    }
+    amount - fee
+  }
+
+  fn credit_treasury(&mut self, token_id: &AccountId, amount: u128) {
+    let collected = self.treasury.get(token_id).unwrap_or(0);
+    self.treasury.insert(token_id, &(collected + amount));
+  }
+}
+
+// `bps` basis points of `amount`, split to keep the multiplication from overflowing.
+pub(crate) fn bps_share(amount: u128, bps: u16) -> u128 {
+  let bps = bps as u128;
+  amount / MAX_BPS * bps + amount % MAX_BPS * bps / MAX_BPS
+}
@@ -0,0 +1,68 @@
+use near_sdk::{collections::LazyOption, json_types::Base64VecU8, Gas, Promise};
+
+use crate::{*, utils::{UpgradeProposal, InternalError, DTradeEvent, UpgradeData}};
+
+const GAS_FOR_UPGRADE: Gas = Gas(20_000_000_000_000);
+
+// The staged code lives outside `Contract` so it is only loaded when an upgrade is applied.
+fn staged_code() -> LazyOption<Vec<u8>> {
+  LazyOption::new(b"x", None) // x
+}
+
+fn upgrade_proposal() -> LazyOption<UpgradeProposal> {
+  LazyOption::new(b"y", None) // y
+}
+
+#[near_bindgen]
+impl Contract {
+  // Stages new code; it can only be deployed through `upgrade` once the delay has passed.
+  #[handle_result]
+  pub fn propose_upgrade(&mut self, code: Base64VecU8) -> Result<UpgradeProposal, InternalError> {
+    self.assert_owner()?;
+    let code: Vec<u8> = code.into();
+    let proposal = UpgradeProposal {
+      code_hash: near_sdk::bs58::encode(env::sha256(&code)).into_string(),
+      apply_after: env::block_timestamp_ms() + self.config.upgrade_delay_ms,
+    };
+    staged_code().set(&code);
+    upgrade_proposal().set(&proposal);
+    DTradeEvent::ProposeUpgrade(vec![UpgradeData {
+      code_hash: &proposal.code_hash,
+      apply_after: proposal.apply_after,
+    }]).emit();
+    Ok(proposal)
+  }
+
+  #[handle_result]
+  pub fn cancel_upgrade(&mut self) -> Result<(), InternalError> {
+    self.assert_owner()?;
+    upgrade_proposal().take().ok_or(InternalError::NotFound)?;
+    staged_code().remove();
+    Ok(())
+  }
+
+  pub fn get_upgrade_proposal(&self) -> Option<UpgradeProposal> {
+    upgrade_proposal().get()
+  }
+
+  // Deploys the staged code to this account and runs `migrate` in the same batch, so a failed
+  // migration rolls the deployment back.
+  #[handle_result]
+  pub fn upgrade(&mut self) -> Result<Promise, InternalError> {
+    self.assert_owner()?;
+    let proposal = upgrade_proposal().get().ok_or(InternalError::NotFound)?;
+    if env::block_timestamp_ms() < proposal.apply_after {
+      return Err(InternalError::TooEarly)
+    }
+    // Whatever is left after this call goes to `migrate`
+    let migrate_gas = env::prepaid_gas().0.checked_sub(env::used_gas().0 + GAS_FOR_UPGRADE.0)
+      .map(Gas)
+      .ok_or(InternalError::InsufficientGas)?;
+    let code = staged_code().take().ok_or(InternalError::NotFound)?;
+    upgrade_proposal().remove();
+
+    Ok(Promise::new(env::current_account_id())
+      .deploy_contract(code)
+      .function_call("migrate".to_string(), b"{}".to_vec(), 0, migrate_gas))
+  }
+}
@@ -1,5 +1,9 @@
+use std::{fmt, str::FromStr};
+
 use near_sdk::{borsh::{self, BorshDeserialize, BorshSerialize}, near_bindgen, FunctionError, serde::{Serialize, Deserialize}};
 
+use super::{Order, Proposal, User};
+
 
 #[near_bindgen]
 #[derive(BorshDeserialize, BorshSerialize)]
@@ -10,7 +14,27 @@ pub enum OrderStatus {
  STAGGED,
  SHIPPING,
  DELIVERED,
- CANCELLED
+ CANCELLED,
+ CLAIMED,
+ RESOLVED
+}
+
+impl OrderStatus {
+    // The only legal moves an order can make; DELIVERED, CANCELLED, CLAIMED and RESOLVED are final.
+    pub fn can_transition_to(&self, next: &OrderStatus) -> bool {
+        matches!(
+            (self, next),
+            (OrderStatus::PENDING, OrderStatus::STAGGED)
+                | (OrderStatus::PENDING, OrderStatus::CANCELLED)
+                | (OrderStatus::STAGGED, OrderStatus::SHIPPING)
+                | (OrderStatus::STAGGED, OrderStatus::CANCELLED)
+                | (OrderStatus::SHIPPING, OrderStatus::DELIVERED)
+                | (OrderStatus::SHIPPING, OrderStatus::CLAIMED)
+                | (OrderStatus::PENDING, OrderStatus::RESOLVED)
+                | (OrderStatus::STAGGED, OrderStatus::RESOLVED)
+                | (OrderStatus::SHIPPING, OrderStatus::RESOLVED)
+        )
+    }
 }
 
 #[near_bindgen]
@@ -25,26 +49,209 @@ pub enum Vehicle {
     LORRY
 }
 
+impl Vehicle {
+    // Longest pickup to drop-off distance, in metres, worth offering to a courier with this vehicle.
+    pub fn range_m(&self) -> u64 {
+        match self {
+            Vehicle::MOTORCYCLE => 50_000,
+            Vehicle::TUKTUK => 20_000,
+            Vehicle::CAR => 200_000,
+            Vehicle::PICKUP => 500_000,
+            Vehicle::LORRY => u64::MAX,
+        }
+    }
+}
+
+// Couriers name their vehicle in lowercase wherever the contract takes one as an argument.
+impl FromStr for Vehicle {
+    type Err = InternalError;
+
+    fn from_str(vehicle: &str) -> Result<Self, Self::Err> {
+        match vehicle {
+            "motorcycle" => Ok(Vehicle::MOTORCYCLE),
+            "tuktuk" => Ok(Vehicle::TUKTUK),
+            "car" => Ok(Vehicle::CAR),
+            "pickup" => Ok(Vehicle::PICKUP),
+            "lorry" => Ok(Vehicle::LORRY),
+            _ => Err(InternalError::InvalidPayload(format!("unknown vehicle: {}", vehicle)))
+        }
+    }
+}
+
 #[near_bindgen]
 #[derive(BorshDeserialize, BorshSerialize)]
+#[derive(PartialEq)]
 pub enum ProposalStatus {
     PENDING,
     PROPOSED,
     APPROVED
 }
 
+#[near_bindgen]
+#[derive(BorshDeserialize, BorshSerialize)]
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub enum ClaimReason {
+    LOST,
+    DAMAGED
+}
+
+#[near_bindgen]
+#[derive(BorshDeserialize, BorshSerialize)]
+#[derive(Serialize, Deserialize, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub enum ClaimStatus {
+    PENDING,
+    APPROVED,
+    REJECTED
+}
+
+#[near_bindgen]
+#[derive(BorshDeserialize, BorshSerialize)]
+#[derive(Serialize, Deserialize, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub enum DisputeStatus {
+    OPEN,
+    RESOLVED
+}
+
+#[derive(Debug)]
 pub enum InternalError {
     NotFound,
+    InsufficientFunds,
+    OrderExists,
+    AlreadyExists,
+    UnknownCompany,
+    UnknownToken,
+    CourierNotAssigned,
+    InvalidTransition,
+    InvalidPayload(String),
+    Unauthorized,
+    NotRegistered,
+    InsufficientStorage,
+    StorageInUse,
+    TooEarly,
+    Expired,
+    Disputed,
+    ClaimPending,
+    InsufficientGas,
     Unexpected(String),
 }
 
+impl InternalError {
+    // Stable identifier clients can match on; it prefixes every error message.
+    pub fn code(&self) -> &'static str {
+        match self {
+            InternalError::NotFound => "NOT_FOUND",
+            InternalError::InsufficientFunds => "INSUFFICIENT_FUNDS",
+            InternalError::OrderExists => "ORDER_EXISTS",
+            InternalError::AlreadyExists => "ALREADY_EXISTS",
+            InternalError::UnknownCompany => "UNKNOWN_COMPANY",
+            InternalError::UnknownToken => "UNKNOWN_TOKEN",
+            InternalError::CourierNotAssigned => "COURIER_NOT_ASSIGNED",
+            InternalError::InvalidTransition => "INVALID_TRANSITION",
+            InternalError::InvalidPayload(_) => "INVALID_PAYLOAD",
+            InternalError::Unauthorized => "UNAUTHORIZED",
+            InternalError::NotRegistered => "NOT_REGISTERED",
+            InternalError::InsufficientStorage => "INSUFFICIENT_STORAGE",
+            InternalError::StorageInUse => "STORAGE_IN_USE",
+            InternalError::TooEarly => "TOO_EARLY",
+            InternalError::Expired => "EXPIRED",
+            InternalError::Disputed => "ORDER_DISPUTED",
+            InternalError::ClaimPending => "CLAIM_PENDING",
+            InternalError::InsufficientGas => "INSUFFICIENT_GAS",
+            InternalError::Unexpected(_) => "UNEXPECTED",
+        }
+    }
+}
+
+impl fmt::Display for InternalError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            InternalError::NotFound => write!(f, "{}: not found", self.code()),
+            InternalError::InsufficientFunds => write!(f, "{}: insufficient funds", self.code()),
+            InternalError::OrderExists => write!(f, "{}: order already exists", self.code()),
+            InternalError::AlreadyExists => write!(f, "{}: record already exists", self.code()),
+            InternalError::UnknownCompany => write!(f, "{}: company does not exist", self.code()),
+            InternalError::UnknownToken => write!(f, "{}: token not accepted", self.code()),
+            InternalError::CourierNotAssigned => write!(f, "{}: order has no courier assigned", self.code()),
+            InternalError::InvalidTransition => write!(f, "{}: invalid order status transition", self.code()),
+            InternalError::InvalidPayload(message) => write!(f, "{}: invalid payload: {}", self.code(), message),
+            InternalError::Unauthorized => write!(f, "{}: caller is not allowed to perform this action", self.code()),
+            InternalError::NotRegistered => write!(f, "{}: account has no storage deposit", self.code()),
+            InternalError::InsufficientStorage => write!(f, "{}: storage deposit does not cover usage", self.code()),
+            InternalError::StorageInUse => write!(f, "{}: account still holds contract data", self.code()),
+            InternalError::TooEarly => write!(f, "{}: action is not available yet", self.code()),
+            InternalError::Expired => write!(f, "{}: deadline has passed", self.code()),
+            InternalError::Disputed => write!(f, "{}: order is frozen by an open dispute", self.code()),
+            InternalError::ClaimPending => write!(f, "{}: order has an insurance claim awaiting a decision", self.code()),
+            InternalError::InsufficientGas => write!(f, "{}: not enough gas attached", self.code()),
+            InternalError::Unexpected(message) => write!(f, "{}: unexpected error: {}", self.code(), message),
+        }
+    }
+}
+
 impl FunctionError for InternalError {
     fn panic(&self) -> ! {
-        match self {
-            InternalError::NotFound => 
-                near_sdk::env::panic_str("not found"),
-            InternalError::Unexpected(message) => 
-                near_sdk::env::panic_str(&format!("unexpected error: {}", message))
+        near_sdk::env::panic_str(&self.to_string())
+    }
+}
+
+// Stored entities are wrapped so their layout can change without breaking existing state:
+// add a variant for the new layout and upgrade the older ones in `From`.
+#[derive(BorshDeserialize, BorshSerialize)]
+pub enum VersionedOrder {
+    V1(Order),
+}
+
+impl From<VersionedOrder> for Order {
+    fn from(order: VersionedOrder) -> Self {
+        match order {
+            VersionedOrder::V1(order) => order,
+        }
+    }
+}
+
+impl From<Order> for VersionedOrder {
+    fn from(order: Order) -> Self {
+        VersionedOrder::V1(order)
+    }
+}
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub enum VersionedProposal {
+    V1(Proposal),
+}
+
+impl From<VersionedProposal> for Proposal {
+    fn from(proposal: VersionedProposal) -> Self {
+        match proposal {
+            VersionedProposal::V1(proposal) => proposal,
+        }
+    }
+}
+
+impl From<Proposal> for VersionedProposal {
+    fn from(proposal: Proposal) -> Self {
+        VersionedProposal::V1(proposal)
+    }
+}
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub enum VersionedUser {
+    V1(User),
+}
+
+impl From<VersionedUser> for User {
+    fn from(user: VersionedUser) -> Self {
+        match user {
+            VersionedUser::V1(user) => user,
         }
     }
-}
\ No newline at end of file
+}
+
+impl From<User> for VersionedUser {
+    fn from(user: User) -> Self {
+        VersionedUser::V1(user)
+    }
+}
@@ -0,0 +1,162 @@
+use near_sdk::{env, AccountId, json_types::U128, serde::Serialize, serde_json};
+
+use super::{Coordinate, Vehicle};
+
+pub const EVENT_STANDARD: &str = "dtrade";
+pub const EVENT_STANDARD_VERSION: &str = "1.0.0";
+
+// NEP-297 events, logged as `EVENT_JSON:{"standard":"dtrade","version":"1.0.0","event":...,"data":[...]}`
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+#[serde(tag = "event", content = "data", rename_all = "snake_case")]
+pub enum DTradeEvent<'a> {
+ PlaceOrder(Vec<PlaceOrderData<'a>>),
+ StageOrder(Vec<StageOrderData<'a>>),
+ ApproveProposal(Vec<ApproveProposalData<'a>>),
+ LockBalance(Vec<LockBalanceData<'a>>),
+ Refund(Vec<RefundData<'a>>),
+ RegisterCourier(Vec<RegisterCourierData<'a>>),
+ SaveCompany(Vec<SaveCompanyData<'a>>),
+ ProposeUpgrade(Vec<UpgradeData<'a>>),
+ OpenDispute(Vec<OpenDisputeData<'a>>),
+ ResolveDispute(Vec<ResolveDisputeData<'a>>),
+ PlaceBid(Vec<PlaceBidData<'a>>),
+ WithdrawBid(Vec<WithdrawBidData<'a>>),
+ PostCheckpoint(Vec<PostCheckpointData<'a>>),
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PlaceOrderData<'a> {
+ pub order_id: &'a str,
+ pub buyer_id: &'a AccountId,
+ pub seller_id: &'a AccountId,
+ pub token_id: &'a AccountId,
+ pub amount: U128,
+ pub insurance_premium: U128,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct StageOrderData<'a> {
+ pub order_id: &'a str,
+ pub buyer_id: &'a AccountId,
+ pub seller_id: &'a AccountId,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct ApproveProposalData<'a> {
+ pub order_id: &'a str,
+ pub buyer_id: &'a AccountId,
+ pub courier_id: &'a AccountId,
+ pub amount: U128,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct LockBalanceData<'a> {
+ pub escrow_id: &'a str,
+ pub account_id: &'a AccountId,
+ pub receiver_id: &'a AccountId,
+ pub token_id: &'a AccountId,
+ pub amount: U128,
+ pub deadline: u64,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct RefundData<'a> {
+ pub escrow_id: &'a str,
+ pub account_id: &'a AccountId,
+ pub token_id: &'a AccountId,
+ pub amount: U128,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct RegisterCourierData<'a> {
+ pub courier_id: &'a AccountId,
+ pub vehicle: &'a Vehicle,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SaveCompanyData<'a> {
+ pub courier_id: &'a AccountId,
+ pub company_id: &'a AccountId,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct UpgradeData<'a> {
+ pub code_hash: &'a str,
+ pub apply_after: u64,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct OpenDisputeData<'a> {
+ pub order_id: &'a str,
+ pub buyer_id: &'a AccountId,
+ pub seller_id: &'a AccountId,
+ pub opened_by: &'a AccountId,
+ pub reason: &'a str,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct ResolveDisputeData<'a> {
+ pub order_id: &'a str,
+ pub arbitrator_id: Option<&'a AccountId>,
+ pub buyer_share_bps: u16,
+ pub buyer_amount: U128,
+ pub seller_amount: U128,
+ pub courier_amount: U128,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PlaceBidData<'a> {
+ pub order_id: &'a str,
+ pub courier_id: &'a AccountId,
+ pub fee: U128,
+ pub eta_minutes: u32,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct WithdrawBidData<'a> {
+ pub order_id: &'a str,
+ pub courier_id: &'a AccountId,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PostCheckpointData<'a> {
+ pub order_id: &'a str,
+ pub courier_id: &'a AccountId,
+ pub location: &'a Coordinate,
+ pub note: &'a str,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+struct EventLog<'a> {
+ standard: &'static str,
+ version: &'static str,
+ #[serde(flatten)]
+ event: DTradeEvent<'a>,
+}
+
+impl DTradeEvent<'_> {
+ pub fn emit(self) {
+  let log = EventLog {
+   standard: EVENT_STANDARD,
+   version: EVENT_STANDARD_VERSION,
+   event: self,
+  };
+  let json = serde_json::to_string(&log).unwrap_or_else(|_| env::panic_str("Cannot serialize event!"));
+  env::log_str(&format!("EVENT_JSON:{}", json));
+ }
+}
@@ -0,0 +1,195 @@
+use super::{Coordinate, InternalError};
+
+// Fixed-point values carry 9 decimal places so distances come out the same on every node.
+const SCALE: i128 = 1_000_000_000;
+const PI: i128 = 3_141_592_654;
+const EARTH_RADIUS_M: i128 = 6_371_000;
+const MICRODEGREES_PER_DEGREE: i64 = 1_000_000;
+const METRES_PER_DEGREE: i64 = 111_320;
+
+// Orders are indexed by half-degree cells, roughly 55km across at the equator.
+pub const CELL_MICRODEGREES: i64 = 500_000;
+// Cells around a parallel; longitude cells wrap around at the antimeridian.
+const LON_CELLS: i32 = (360 * MICRODEGREES_PER_DEGREE / CELL_MICRODEGREES) as i32;
+// Near the poles a small radius spans every meridian, so searches stop at the nearest cells.
+const MAX_SEARCH_CELLS: usize = 100;
+
+pub type GridCell = (i32, i32);
+
+// Coordinates come from callers; everything below assumes they are on the globe.
+pub fn check_coordinate(coordinate: &Coordinate) -> Result<(), InternalError> {
+    let max_lat = 90 * MICRODEGREES_PER_DEGREE;
+    let max_lon = 180 * MICRODEGREES_PER_DEGREE;
+    if !(-max_lat..=max_lat).contains(&coordinate.lat) || !(-max_lon..=max_lon).contains(&coordinate.lon) {
+        return Err(InternalError::InvalidPayload("coordinates must be within ±90° latitude and ±180° longitude".to_string()))
+    }
+    Ok(())
+}
+
+// Great-circle distance between two coordinates, in metres.
+pub fn distance_m(from: &Coordinate, to: &Coordinate) -> u64 {
+    let (from_lat, to_lat) = (radians(from.lat), radians(to.lat));
+    let half_delta_lat = sin((to_lat - from_lat) / 2);
+    let half_delta_lon = sin((radians(to.lon) - radians(from.lon)) / 2);
+    let a = mul(half_delta_lat, half_delta_lat)
+        + mul(mul(cos(from_lat), cos(to_lat)), mul(half_delta_lon, half_delta_lon));
+    (2 * EARTH_RADIUS_M * asin(sqrt(a.clamp(0, SCALE))) / SCALE) as u64
+}
+
+pub fn grid_cell(coordinate: &Coordinate) -> GridCell {
+    (
+        coordinate.lat.div_euclid(CELL_MICRODEGREES) as i32,
+        wrap_lon_cell(coordinate.lon.div_euclid(CELL_MICRODEGREES) as i32),
+    )
+}
+
+// Every cell holding a point within `radius_m` of `center`, nearest first and at most
+// `MAX_SEARCH_CELLS` of them.
+pub fn cells_within(center: &Coordinate, radius_m: u64) -> Vec<GridCell> {
+    let lat_span = radius_m as i64 * MICRODEGREES_PER_DEGREE / METRES_PER_DEGREE;
+    // Longitude degrees shrink towards the poles, so the same radius spans more of them
+    let widest_lat = center.lat.abs() + lat_span;
+    let lon_span = if widest_lat >= 90 * MICRODEGREES_PER_DEGREE {
+        180 * MICRODEGREES_PER_DEGREE
+    } else {
+        let cos_lat = cos(radians(widest_lat)).max(1);
+        (lat_span as i128 * SCALE / cos_lat).min(180 * MICRODEGREES_PER_DEGREE as i128) as i64
+    };
+
+    let center_lat = center.lat.div_euclid(CELL_MICRODEGREES) as i32;
+    let center_lon = center.lon.div_euclid(CELL_MICRODEGREES) as i32;
+    let min_lat = (center.lat - lat_span).max(-90 * MICRODEGREES_PER_DEGREE).div_euclid(CELL_MICRODEGREES) as i32;
+    let max_lat = (center.lat + lat_span).min(90 * MICRODEGREES_PER_DEGREE).div_euclid(CELL_MICRODEGREES) as i32;
+    // Columns past the antimeridian are wrapped below; a span never covers the parallel twice
+    let west = (center.lon - lon_span).div_euclid(CELL_MICRODEGREES) as i32;
+    let east = ((center.lon + lon_span).div_euclid(CELL_MICRODEGREES) as i32).min(west + LON_CELLS - 1);
+    let mut cells: Vec<(i32, GridCell)> = (min_lat..=max_lat).flat_map(|lat| {
+        (west..=east).map(move |lon| ((lat - center_lat).abs().max((lon - center_lon).abs()), (lat, wrap_lon_cell(lon))))
+    }).collect();
+    cells.sort_by_key(|(ring, _)| *ring);
+    cells.into_iter().take(MAX_SEARCH_CELLS).map(|(_, cell)| cell).collect()
+}
+
+fn wrap_lon_cell(lon_cell: i32) -> i32 {
+    (lon_cell + LON_CELLS / 2).rem_euclid(LON_CELLS) - LON_CELLS / 2
+}
+
+fn radians(microdegrees: i64) -> i128 {
+    microdegrees as i128 * PI / (180 * MICRODEGREES_PER_DEGREE as i128)
+}
+
+fn mul(a: i128, b: i128) -> i128 {
+    a * b / SCALE
+}
+
+fn sin(x: i128) -> i128 {
+    let mut x = x % (2 * PI);
+    if x > PI {
+        x -= 2 * PI;
+    } else if x < -PI {
+        x += 2 * PI;
+    }
+    // Taylor series, each term is the previous one times -x² / ((2n)(2n + 1))
+    let x_squared = mul(x, x);
+    let (mut term, mut sum, mut n) = (x, x, 1);
+    while term != 0 {
+        term = -mul(term, x_squared) / ((2 * n) * (2 * n + 1));
+        sum += term;
+        n += 1;
+    }
+    sum
+}
+
+fn cos(x: i128) -> i128 {
+    sin(x + PI / 2)
+}
+
+fn sqrt(x: i128) -> i128 {
+    let value = x * SCALE;
+    if value == 0 {
+        return 0
+    }
+    let mut root = value;
+    let mut next = (root + 1) / 2;
+    while next < root {
+        root = next;
+        next = (root + value / root) / 2;
+    }
+    root
+}
+
+// Only defined on [0, 1]. Inputs above 1/√2 go through asin(x) = π/2 - asin(√(1 - x²)) so the
+// series is always evaluated where it converges quickly.
+fn asin(x: i128) -> i128 {
+    if x > SCALE * 7072 / 10_000 {
+        return PI / 2 - asin(sqrt(SCALE - mul(x, x)))
+    }
+    let x_squared = mul(x, x);
+    let (mut term, mut sum, mut n) = (x, x, 1);
+    while term != 0 {
+        term = mul(term, x_squared) * (2 * n - 1) / (2 * n);
+        sum += term / (2 * n + 1);
+        n += 1;
+    }
+    sum
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(lat: i64, lon: i64) -> Coordinate {
+        Coordinate { lat, lon }
+    }
+
+    #[test]
+    fn distance_between_known_cities() {
+        // Nairobi to Mombasa is about 441km, London to Sydney about 16994km
+        assert_eq!(distance_m(&at(-1_286_389, 36_817_223), &at(-4_043_477, 39_668_206)) / 1000, 440);
+        assert_eq!(distance_m(&at(51_507_351, -127_758), &at(-33_868_820, 151_209_296)) / 1000, 16_993);
+        assert_eq!(distance_m(&at(10_000_000, 20_000_000), &at(10_000_000, 20_000_000)), 0);
+    }
+
+    #[test]
+    fn distance_across_antimeridian_is_short() {
+        assert!(distance_m(&at(0, 179_900_000), &at(0, -179_900_000)) < 23_000);
+    }
+
+    #[test]
+    fn grid_cell_floors_towards_south_west() {
+        assert_eq!(grid_cell(&at(0, 0)), (0, 0));
+        assert_eq!(grid_cell(&at(499_999, 500_000)), (0, 1));
+        assert_eq!(grid_cell(&at(-1, -1)), (-1, -1));
+        assert_eq!(grid_cell(&at(-90_000_000, -180_000_000)), (-180, -360));
+        // The antimeridian belongs to a single column
+        assert_eq!(grid_cell(&at(90_000_000, 180_000_000)), (180, -360));
+    }
+
+    #[test]
+    fn cells_within_start_at_center() {
+        assert_eq!(cells_within(&at(-1_286_389, 36_817_223), 10_000), vec![(-3, 73)]);
+        // Close to a corner the radius reaches into the three neighbouring cells as well
+        let cells = cells_within(&at(10_000, 10_000), 10_000);
+        assert_eq!(cells[0], (0, 0));
+        assert_eq!(cells.len(), 4);
+        assert!(cells.contains(&(-1, -1)));
+    }
+
+    #[test]
+    fn cells_within_wrap_around_antimeridian() {
+        let cells = cells_within(&at(0, 179_900_000), 30_000);
+        assert!(cells.contains(&grid_cell(&at(0, -179_900_000))));
+        assert!(cells.iter().all(|(_, lon)| (-360..360).contains(lon)));
+    }
+
+    #[test]
+    fn cells_within_are_capped_near_poles() {
+        let cells = cells_within(&at(89_900_000, 0), 100_000);
+        assert_eq!(cells.len(), MAX_SEARCH_CELLS);
+        assert_eq!(cells[0], (179, 0));
+        let mut unique = cells.clone();
+        unique.sort();
+        unique.dedup();
+        assert_eq!(unique.len(), cells.len());
+    }
+}
@@ -1,5 +1,9 @@
 mod objects;
 mod enums;
+mod events;
+mod geo;
 
 pub use objects::*;
-pub use enums::*;
\ No newline at end of file
+pub use enums::*;
+pub use events::*;
+pub use geo::*;
\ No newline at end of file
@@ -1,6 +1,6 @@
-use near_sdk::{AccountId, near_bindgen, borsh::{self, BorshDeserialize, BorshSerialize}, serde::{Serialize, Deserialize}};
+use near_sdk::{AccountId, near_bindgen, json_types::U128, borsh::{self, BorshDeserialize, BorshSerialize}, serde::{Serialize, Deserialize}};
 
-use super::{OrderStatus, Vehicle, ProposalStatus};
+use super::{GridCell, OrderStatus, Vehicle, ProposalStatus, ClaimReason, ClaimStatus, DisputeStatus};
 
 #[near_bindgen]
 #[derive(Serialize, Deserialize, Debug)]
@@ -8,7 +8,7 @@ use super::{OrderStatus, Vehicle, ProposalStatus};
 pub struct CartItem {
  pub name: String,
  pub serial: String,
- pub price: f64,
+ pub price: U128, // per unit, in the token's smallest unit
  pub quantity: u16,
  pub reference: String,
 }
@@ -17,11 +17,12 @@ pub struct CartItem {
 #[derive(Serialize, Deserialize)]
 #[serde(crate = "near_sdk::serde")]
 pub struct Cart {
- pub seller: String,
+ pub seller: AccountId,
  pub location: Coordinate,
  pub percentage_insurance: u8,
  pub list_for_bidding: bool,
  pub items: Vec<CartItem>,
+ pub delivery_code_hash: Option<String>, // base58 sha256 of the 128+ bit random code revealed through `deliver`
 }
 
 #[near_bindgen]
@@ -42,12 +43,14 @@ pub struct OrderItem {
 #[serde(crate = "near_sdk::serde")]
 pub struct Order {
  pub amount: u128,
- pub seller: String,
+ pub token_id: AccountId,
+ pub seller: AccountId,
  pub status: OrderStatus,
  pub insurance: u8,
- pub courier: Option<String>,
+ pub courier: Option<AccountId>,
  pub timestamp: u64,
  pub location: Coordinate,
+ pub cancel_requested: bool, // by the buyer, while the order is staged
 }
 
 #[near_bindgen]
@@ -126,13 +129,14 @@ pub struct CourierUser {
 #[derive(Serialize, Deserialize)]
 #[serde(crate = "near_sdk::serde")]
 pub struct CourierClientView {
- pub id: String,
+ pub id: AccountId,
  pub name: String,
  pub image: String,
  pub phone: String,
  pub on_transit: bool,
  pub feedback: Option<Feedback>,
  pub proposed_fee: u128,
+ pub eta_minutes: Option<u32>,
 }
 
 #[near_bindgen]
@@ -140,7 +144,7 @@ pub struct CourierClientView {
 #[serde(crate = "near_sdk::serde")]
 pub struct ProposalApproval {
  pub order_id: String,
- pub courier_id: String,
+ pub courier_id: AccountId,
 }
 
 #[near_bindgen]
@@ -153,7 +157,10 @@ pub struct CourierProfile {
 
 #[near_bindgen]
 #[derive(BorshDeserialize, BorshSerialize)]
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
 pub struct Company {
+ pub owner: AccountId,
  pub name: String,
  pub wallet: AccountId,
  pub phone: String,
@@ -163,23 +170,76 @@ pub struct Company {
  pub star_rate: StarRate,
 }
 
+#[near_bindgen]
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct CompanyDetails {
+ pub name: String,
+ pub wallet: AccountId,
+ pub phone: String,
+ pub email: String,
+ pub location: Coordinate,
+}
+
+#[near_bindgen]
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct CompanyView {
+ pub id: AccountId,
+ pub metadata: Company,
+}
+
+#[near_bindgen]
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct CompanyBundle {
+ pub next_page: u16,
+ pub companies: Vec<CompanyView>
+}
+
 #[near_bindgen]
 #[derive(BorshDeserialize, BorshSerialize)]
 #[derive(Serialize, Deserialize)]
 #[serde(crate = "near_sdk::serde")]
 pub struct Coordinate {
- pub lat: i64,
- pub lon: i64,
+ pub lat: i64, // microdegrees
+ pub lon: i64, // microdegrees
 }
 
 #[near_bindgen]
 #[derive(BorshDeserialize, BorshSerialize)]
 pub struct Proposal {
- pub client: String,
+ pub client: AccountId,
  pub courier_id: Option<AccountId>,
  pub amount: u128,
  pub fee: u128,
- pub status: ProposalStatus
+ pub status: ProposalStatus,
+ pub eta_minutes: Option<u32>, // set by couriers bidding on the board
+}
+
+#[near_bindgen]
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct OpenOrder {
+ pub buyer_id: AccountId,
+ pub bid_deadline: u64,
+ pub cell: GridCell, // of the pickup location, see `orders_by_cell`
+}
+
+#[near_bindgen]
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct OpenOrderView {
+ pub buyer_id: AccountId,
+ pub bid_deadline: u64,
+ pub order: UserOrder,
+}
+
+#[near_bindgen]
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct OpenOrderBundle {
+ pub next_page: u16,
+ pub orders: Vec<OpenOrderView>
 }
 
 #[near_bindgen]
@@ -192,7 +252,97 @@ pub struct Account {
 
 #[near_bindgen]
 #[derive(BorshDeserialize, BorshSerialize)]
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
 pub struct LockedAmount {
  pub receiver_id: AccountId,
+ pub token_id: AccountId,
  pub amount: u128,
-}
\ No newline at end of file
+ pub deadline: u64, // after it, anyone may settle the escrow through `claim_expired`
+ pub courier_amount: u128, // paid by the buyer on approving a courier, settled with the order
+}
+#[near_bindgen]
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct StorageAccount {
+ pub deposit: u128,
+ pub used_bytes: u64,
+}
+
+#[near_bindgen]
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct StorageBalance {
+ pub total: U128,
+ pub available: U128,
+}
+
+#[near_bindgen]
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct StorageBalanceBounds {
+ pub min: U128,
+ pub max: Option<U128>,
+}
+
+#[near_bindgen]
+#[derive(BorshDeserialize, BorshSerialize)]
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Config {
+ // Seeds `accepted_tokens` at init and is filled from it by `get_config`, never stored here
+ #[borsh_skip]
+ pub accepted_tokens: Vec<(AccountId, u8)>,
+ pub platform_fee_bps: u16,
+ pub dispute_window_ms: u64, // how long after an order is placed either party may open a dispute
+ pub arbitration_timeout_ms: u64, // how long arbitrators have before a dispute can be settled by default
+ pub shipping_timeout_ms: u64, // how long a seller has to ship before the buyer can be refunded
+ pub bidding_window_ms: u64, // how long couriers may bid on an order listed for bidding
+ pub confirmation_window_ms: u64, // how long a buyer has to confirm or dispute a shipped order
+ pub upgrade_delay_ms: u64, // how long users get to review a proposed upgrade before it can be applied
+}
+
+#[near_bindgen]
+#[derive(BorshDeserialize, BorshSerialize)]
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct UpgradeProposal {
+ pub code_hash: String, // base58 sha256 of the staged code
+ pub apply_after: u64,
+}
+
+#[near_bindgen]
+#[derive(BorshDeserialize, BorshSerialize)]
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct InsuranceClaim {
+ pub buyer_id: AccountId,
+ pub reason: ClaimReason,
+ pub status: ClaimStatus,
+ pub filed_at: u64,
+ pub payout: u128,
+}
+
+#[near_bindgen]
+#[derive(BorshDeserialize, BorshSerialize)]
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Dispute {
+ pub buyer_id: AccountId,
+ pub seller_id: AccountId,
+ pub opened_by: AccountId,
+ pub reason: String,
+ pub opened_at: u64,
+ pub status: DisputeStatus,
+ pub buyer_share_bps: Option<u16>,
+ pub resolved_by: Option<AccountId>,
+}
+
+#[near_bindgen]
+#[derive(BorshDeserialize, BorshSerialize)]
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Checkpoint {
+ pub timestamp: u64,
+ pub location: Coordinate,
+ pub note: String,
+}